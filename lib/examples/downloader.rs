@@ -1,22 +1,22 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
-use hermes::downloader::{DownloadKey, DownloadPool, Event, Update};
+use hermes::{
+    downloader::{DownloadKey, DownloadPool, Event, Update},
+    testing::{Fault, MockRepository, MockServer},
+};
 use indicatif::{MultiProgress, ProgressBar};
 
 #[tokio::main]
 async fn main() {
-    let urls = vec![
-        "https://images.unsplash.com/photo-1650409476524-7eb2f71b6cc8",
-        "https://images.unsplash.com/photo-1660101077376-08ffebd701f4",
-        "https://images.unsplash.com/photo-1664262900842-45eb7101f131",
-        "https://images.unsplash.com/photo-1649153512379-c86b9d06739d",
-        "https://images.unsplash.com/photo-1643666183207-075183a0e3be",
-        "https://images.unsplash.com/photo-1640462709185-77f39fc4a91f",
-        "https://images.unsplash.com/photo-1640462709185-77f39fc4a91f",
-        "https://images.unsplash.com/photo-1640229318581-8873d30a10a9",
-        "https://images.unsplash.com/photo-1640299438750-a9a4a39d397e",
-        "https://images.unsplash.com/photo-1640185623400-dc00cfacd1a5",
-    ];
+    let mut repository = MockRepository::new("example");
+    for i in 0..10 {
+        repository = repository.with_random_file(format!("@mod/addons/{i}.pbo"), 4 * 1024 * 1024);
+    }
+    let server = MockServer::serve(&repository).await.unwrap();
+    // Show how failures are retried
+    server.inject("*", Fault::Latency(Duration::from_millis(200)), None);
+    server.inject("@mod/addons/3.pbo", Fault::Status(503), Some(2));
+    server.inject("@mod/addons/7.pbo", Fault::Reset(1024 * 1024), Some(1));
 
     let pool = DownloadPool::new(4, Some(1024 * 1024 * 3)).await;
 
@@ -25,9 +25,12 @@ async fn main() {
 
     let mut sub = pool.subscribe();
     tokio::spawn(async move {
-        while let Ok(event) = sub.recv().await {
+        while let Some(event) = sub.recv().await {
             match event {
                 Event::WorkerUpdate(update) => match update {
+                    Update::Started { id, key } => {
+                        pbs[&id].set_message(key.url().to_string());
+                    }
                     Update::Progress {
                         id,
                         key,
@@ -43,10 +46,27 @@ async fn main() {
                         pbs[&id].set_position(downloaded);
                         pbs[&id].set_length(total);
                     }
+                    Update::Retrying {
+                        id,
+                        key,
+                        attempt,
+                        delay,
+                        error,
+                    } => {
+                        pbs[&id].println(format!(
+                            "Retrying {} in {:?} after attempt {attempt}: {error}",
+                            key.url(),
+                            delay
+                        ));
+                    }
                     Update::Done(id, key, _) => {
                         pbs[&id].println(format!("Done: {}", key.url()));
                         pbs[&id].finish();
                     }
+                    Update::Failed(id, key, error) => {
+                        pbs[&id].println(format!("Failed: {} {error}", key.url()));
+                        pbs[&id].finish();
+                    }
                 },
                 Event::WorkerAdded(id) => {
                     let pb = ProgressBar::new(100);
@@ -65,15 +85,44 @@ async fn main() {
                         mpb.remove(&pb);
                     }
                 }
+                Event::Stats(None, stats) => {
+                    let eta = stats
+                        .eta()
+                        .map_or_else(|| "unknown".to_string(), |eta| format!("{eta:.0?}"));
+                    mpb.println(format!(
+                        "{}/{} at {}/s, ETA {eta}",
+                        human_bytes::human_bytes(stats.done() as f64),
+                        human_bytes::human_bytes(stats.queued() as f64),
+                        human_bytes::human_bytes(stats.speed()),
+                    ))
+                    .unwrap();
+                }
+                Event::Failed { key, error, .. } => {
+                    mpb.println(format!("Gave up on {}: {error}", key.url()))
+                        .unwrap();
+                }
+                Event::WindowChanged(_)
+                | Event::ConcurrencyChanged(_)
+                | Event::Stats(..)
+                | Event::Queued { .. }
+                | Event::Started { .. }
+                | Event::Retrying { .. }
+                | Event::Cancelled { .. }
+                | Event::Completed { .. } => {}
             }
         }
     });
 
-    for url in urls {
-        pool.download(DownloadKey::new(url.to_string(), None)).await;
+    let mut handles = Vec::new();
+    for (path, _) in repository.files() {
+        handles.push(
+            pool.download(DownloadKey::new(server.url(path), None))
+                .await,
+        );
     }
 
-    while pool.current_concurrent() != 0 {
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    for handle in handles {
+        let _ = handle.await;
     }
+    pool.shutdown().await;
 }
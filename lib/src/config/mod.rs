@@ -2,13 +2,16 @@ use std::{collections::HashMap, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
-use crate::repo::{Pack, Server, Unit};
+use crate::repo::{Encoding, Pack, Server, Unit};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     unit: Unit,
     pack: HashMap<String, Pack>,
     server: HashMap<String, Server>,
+    /// Publish compressed copies of the files with each encoding.
+    #[serde(default)]
+    compress: Vec<Encoding>,
 }
 
 impl Config {
@@ -32,6 +35,10 @@ impl Config {
         self.pack.values()
     }
 
+    pub fn compress(&self) -> &[Encoding] {
+        &self.compress
+    }
+
     pub fn server(&self, name: &str) -> Option<&Server> {
         self.server.get(name)
     }
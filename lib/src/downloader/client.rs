@@ -0,0 +1,366 @@
+use std::{sync::Arc, time::Duration};
+
+use reqwest::{
+    redirect::Policy, Certificate, Client, ClientBuilder, NoProxy, Proxy as ReqwestProxy,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, SignatureScheme,
+};
+
+use super::host::fingerprint;
+use crate::repo::Password;
+
+/// Redirects followed before giving up, as many as reqwest follows by default.
+const MAX_REDIRECTS: usize = 10;
+
+/// Where requests are sent through.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Proxy {
+    /// Use the proxy from the `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and `NO_PROXY` environment variables.
+    #[default]
+    System,
+    /// Connect directly.
+    None,
+    /// Use an `http://`, `https://`, `socks5://` or `socks5h://` proxy for every request.
+    Manual {
+        url: String,
+        /// Username and password for the proxy.
+        auth: Option<(String, Password)>,
+        /// Comma separated hosts to connect to directly, in the format of `NO_PROXY`.
+        no_proxy: Option<String>,
+    },
+}
+
+/// Settings for the HTTP client shared by all workers of a pool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientConfig {
+    user_agent: String,
+    /// How long to wait for a connection to be established.
+    connect_timeout: Duration,
+    /// How long to wait for the response, and then for each chunk of the body.
+    read_timeout: Duration,
+    /// Abort a transfer that averages less than this many bytes per second over the duration.
+    low_speed: Option<(u64, Duration)>,
+    /// How long a whole request may take, `None` to let large files take as long as they need.
+    timeout: Option<Duration>,
+    /// How long an idle connection is kept open for reuse, `None` to keep it open.
+    idle_timeout: Option<Duration>,
+    /// The most idle connections kept open per host.
+    max_idle_per_host: usize,
+    /// The interval of TCP keep-alive probes, `None` to disable them.
+    tcp_keepalive: Option<Duration>,
+    /// Negotiate HTTP/2 with servers that support it, so downloads from one host share a connection.
+    http2: bool,
+    proxy: Proxy,
+    /// PEM certificates trusted in addition to the system's, for self-hosted private CAs.
+    root_certificates: Vec<Vec<u8>>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            user_agent: concat!("hermes/", env!("CARGO_PKG_VERSION")).to_string(),
+            connect_timeout: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(60),
+            low_speed: Some((1024, Duration::from_secs(60))),
+            // Stalled transfers are caught by the read and low speed timeouts instead
+            timeout: None,
+            idle_timeout: Some(Duration::from_secs(90)),
+            max_idle_per_host: 16,
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            http2: true,
+            proxy: Proxy::default(),
+            root_certificates: Vec::new(),
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn with_user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Abort an attempt when no data arrives for `timeout`, reported as [`Timeout::Idle`].
+    ///
+    /// The response may take the connect timeout on top, reported as [`Timeout::Response`].
+    ///
+    /// [`Timeout::Idle`]: super::Timeout::Idle
+    /// [`Timeout::Response`]: super::Timeout::Response
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Abort an attempt averaging less than `bytes_per_second` over `duration`, reported as
+    /// [`Timeout::LowSpeed`]. Time spent waiting on the rate limit isn't counted.
+    ///
+    /// [`Timeout::LowSpeed`]: super::Timeout::LowSpeed
+    pub fn with_low_speed(mut self, low_speed: Option<(u64, Duration)>) -> Self {
+        self.low_speed = low_speed;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn with_max_idle_per_host(mut self, max: usize) -> Self {
+        self.max_idle_per_host = max;
+        self
+    }
+
+    pub fn with_tcp_keepalive(mut self, interval: Option<Duration>) -> Self {
+        self.tcp_keepalive = interval;
+        self
+    }
+
+    pub fn with_http2(mut self, http2: bool) -> Self {
+        self.http2 = http2;
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Trust a PEM encoded root certificate, on top of the system's.
+    pub fn with_root_certificate(mut self, pem: Vec<u8>) -> Self {
+        self.root_certificates.push(pem);
+        self
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        self.read_timeout
+    }
+
+    pub fn low_speed(&self) -> Option<(u64, Duration)> {
+        self.low_speed
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    pub fn max_idle_per_host(&self) -> usize {
+        self.max_idle_per_host
+    }
+
+    pub fn tcp_keepalive(&self) -> Option<Duration> {
+        self.tcp_keepalive
+    }
+
+    pub fn http2(&self) -> bool {
+        self.http2
+    }
+
+    pub fn proxy(&self) -> &Proxy {
+        &self.proxy
+    }
+
+    pub fn root_certificates(&self) -> &[Vec<u8>] {
+        &self.root_certificates
+    }
+
+    pub(crate) fn build(&self) -> reqwest::Result<Client> {
+        self.builder()?.build()
+    }
+
+    /// A client for a host, only connecting to it if it presents the certificate with the
+    /// hex encoded SHA-256 fingerprint `pin`, and only following redirects to its own origin
+    /// if `private`.
+    ///
+    /// The pin is checked during the handshake, so nothing is sent to another server. It
+    /// replaces the usual checks of the certificate, so self-signed certificates can be pinned.
+    /// reqwest only strips `Authorization` and `Cookie` from requests redirected elsewhere,
+    /// so a host with other secrets in its headers or query string must be `private`.
+    pub(crate) fn build_for(&self, pin: Option<&str>, private: bool) -> reqwest::Result<Client> {
+        let mut builder = self.builder()?;
+        if private {
+            builder = builder.redirect(Policy::custom(|attempt| {
+                let origin = attempt.previous().first().map(reqwest::Url::origin);
+                if origin.is_some_and(|origin| origin != attempt.url().origin()) {
+                    // Answered with the redirect itself, which isn't worth retrying
+                    attempt.stop()
+                } else if attempt.previous().len() > MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else {
+                    attempt.follow()
+                }
+            }));
+        }
+        match pin {
+            Some(pin) => builder.use_preconfigured_tls(self.pinned_tls(pin)).build(),
+            None => builder.build(),
+        }
+    }
+
+    /// TLS settings only trusting the certificate with the fingerprint `pin`.
+    fn pinned_tls(&self, pin: &str) -> rustls::ClientConfig {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinVerifier {
+                pin: pin.to_string(),
+                provider,
+            }))
+            .with_no_client_auth();
+        // reqwest leaves the protocols of a preconfigured client to us
+        tls.alpn_protocols = if self.http2 {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        };
+        tls
+    }
+
+    fn builder(&self) -> reqwest::Result<ClientBuilder> {
+        let mut builder = ClientBuilder::new()
+            .user_agent(&self.user_agent)
+            .connect_timeout(self.connect_timeout)
+            .pool_idle_timeout(self.idle_timeout)
+            .pool_max_idle_per_host(self.max_idle_per_host)
+            .tcp_keepalive(self.tcp_keepalive)
+            .tcp_nodelay(true);
+        builder = match &self.proxy {
+            // reqwest reads the environment variables by default
+            Proxy::System => builder,
+            Proxy::None => builder.no_proxy(),
+            Proxy::Manual {
+                url,
+                auth,
+                no_proxy,
+            } => {
+                let mut proxy = ReqwestProxy::all(url)?
+                    .no_proxy(no_proxy.as_deref().and_then(NoProxy::from_string));
+                if let Some((username, password)) = auth {
+                    proxy = proxy.basic_auth(username, password.reveal());
+                }
+                builder.proxy(proxy)
+            }
+        };
+        for pem in &self.root_certificates {
+            builder = builder.add_root_certificate(Certificate::from_pem(pem)?);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        builder = if self.http2 {
+            // Large files are streamed, let the window grow with the connection.
+            builder.http2_adaptive_window(true)
+        } else {
+            builder.http1_only()
+        };
+        Ok(builder)
+    }
+}
+
+/// Accepts only the pinned certificate, still checking the server holds its key.
+#[derive(Debug)]
+struct PinVerifier {
+    pin: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.pin {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let config = ClientConfig::default().with_proxy(Proxy::Manual {
+            url: "socks5h://127.0.0.1:1080".to_string(),
+            auth: Some(("user".to_string(), Password::new("secret".to_string()))),
+            no_proxy: Some("localhost,.internal".to_string()),
+        });
+        assert!(config.build().is_ok());
+        assert!(!format!("{config:?}").contains("secret"));
+
+        assert!(config.build_for(Some(&"ab".repeat(32)), true).is_ok());
+
+        let config = ClientConfig::default().with_root_certificate(b"not a certificate".to_vec());
+        assert!(config.build().is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use std::io::Write;
+
+use crate::repo::Encoding;
+
+use super::error::DownloadError;
+
+/// Decompresses a compressed download as its chunks arrive.
+pub(crate) enum Decoder {
+    Zstd(zstd::stream::zio::Writer<Vec<u8>, zstd::stream::raw::Decoder<'static>>),
+    Gzip(flate2::write::GzDecoder<Vec<u8>>),
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> Result<Self, DownloadError> {
+        Ok(match encoding {
+            Encoding::Zstd => Self::Zstd(zstd::stream::zio::Writer::new(
+                Vec::new(),
+                zstd::stream::raw::Decoder::new().map_err(corrupt)?,
+            )),
+            Encoding::Gzip => Self::Gzip(flate2::write::GzDecoder::new(Vec::new())),
+        })
+    }
+
+    /// Decompress a chunk, returning the bytes it completes.
+    pub fn decode(&mut self, chunk: &[u8]) -> Result<&[u8], DownloadError> {
+        match self {
+            Self::Zstd(decoder) => {
+                decoder.writer_mut().clear();
+                decoder.write_all(chunk).map_err(corrupt)?;
+                decoder.flush().map_err(corrupt)?;
+                Ok(decoder.writer())
+            }
+            Self::Gzip(decoder) => {
+                decoder.get_mut().clear();
+                decoder.write_all(chunk).map_err(corrupt)?;
+                decoder.flush().map_err(corrupt)?;
+                Ok(decoder.get_ref())
+            }
+        }
+    }
+
+    /// The bytes left once the whole download is decompressed.
+    pub fn finish(self) -> Result<Vec<u8>, DownloadError> {
+        match self {
+            Self::Zstd(mut decoder) => {
+                decoder.writer_mut().clear();
+                // Unlike flushing, fails if the stream ended in the middle of a frame
+                decoder.finish().map_err(corrupt)?;
+                Ok(decoder.into_inner().0)
+            }
+            Self::Gzip(mut decoder) => {
+                decoder.get_mut().clear();
+                decoder.finish().map_err(corrupt)
+            }
+        }
+    }
+}
+
+fn corrupt(e: std::io::Error) -> DownloadError {
+    DownloadError::Verification(format!("failed to decompress: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let data = "class CfgPatches {};\n".repeat(1000).into_bytes();
+        let zstd = zstd::encode_all(&data[..], 3).unwrap();
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&data).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        for (encoding, compressed) in [(Encoding::Zstd, zstd), (Encoding::Gzip, gzip)] {
+            let mut decoder = Decoder::new(encoding).unwrap();
+            let mut decoded = Vec::new();
+            for chunk in compressed.chunks(7) {
+                decoded.extend_from_slice(decoder.decode(chunk).unwrap());
+            }
+            decoded.extend(decoder.finish().unwrap());
+            assert_eq!(decoded, data);
+
+            // A stream cut short is caught
+            let mut decoder = Decoder::new(encoding).unwrap();
+            decoder.decode(&compressed[..compressed.len() - 8]).unwrap();
+            assert!(matches!(
+                decoder.finish(),
+                Err(DownloadError::Verification(_))
+            ));
+
+            let mut decoder = Decoder::new(encoding).unwrap();
+            assert!(matches!(
+                decoder.decode(&data[..100]),
+                Err(DownloadError::Verification(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::fmt::Display;
+
+/// Why a download failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadError {
+    /// The request could not be sent, or the connection dropped mid-transfer.
+    Connection(String),
+    /// The server responded with an unsuccessful status code.
+    Status(u16),
+    /// The download could not be written to its sink.
+    Io(String),
+    /// The download was cancelled, or the pool shut down.
+    Cancelled,
+    /// The host didn't present its pinned certificate.
+    CertificateMismatch(String),
+    /// There is no transport for the scheme of the URL.
+    UnsupportedScheme(String),
+    /// The download doesn't match the size or hash its key expects.
+    Verification(String),
+    /// An attempt was aborted by one of the timeouts of the client config.
+    Timeout(Timeout),
+}
+
+/// Which timeout aborted an attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timeout {
+    /// No connection could be established in time.
+    Connect,
+    /// The server didn't respond to the request in time.
+    Response,
+    /// No data was received in time.
+    Idle,
+    /// The transfer was too slow for too long.
+    LowSpeed,
+}
+
+impl DownloadError {
+    /// Can the download succeed if it is tried again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            // Another attempt may be sent intact, or by another mirror
+            Self::Connection(_) | Self::Verification(_) | Self::Timeout(_) => true,
+            Self::Status(status) => matches!(status, 408 | 425 | 429 | 500..=599),
+            Self::Io(_)
+            | Self::Cancelled
+            | Self::CertificateMismatch(_)
+            | Self::UnsupportedScheme(_) => false,
+        }
+    }
+}
+
+impl Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connection(e) => write!(f, "Connection failed: {e}"),
+            Self::Status(status) => write!(f, "Server responded with status {status}"),
+            Self::Io(e) => write!(f, "Failed to write download: {e}"),
+            Self::Cancelled => write!(f, "Download was cancelled"),
+            Self::CertificateMismatch(host) => {
+                write!(f, "Certificate of {host} does not match its pin")
+            }
+            Self::UnsupportedScheme(scheme) => write!(f, "Unsupported URL scheme: {scheme}"),
+            Self::Verification(e) => write!(f, "Verification failed: {e}"),
+            Self::Timeout(Timeout::Connect) => write!(f, "Timed out connecting"),
+            Self::Timeout(Timeout::Response) => write!(f, "Timed out waiting for a response"),
+            Self::Timeout(Timeout::Idle) => write!(f, "Timed out waiting for data"),
+            Self::Timeout(Timeout::LowSpeed) => write!(f, "Transfer was too slow"),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
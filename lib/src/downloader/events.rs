@@ -0,0 +1,258 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+use super::{
+    error::DownloadError, pool::DownloadKey, schedule::Window, sink::Output, stats::Stats,
+    worker::Update,
+};
+
+/// Events a subscriber can hold before snapshots are dropped to make room.
+const CAPACITY: usize = 64;
+/// Events a subscriber can hold at all, the oldest lifecycle events are dropped past it.
+const MAX_EVENTS: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    WorkerUpdate(Update),
+    WorkerAdded(u8),
+    WorkerRemoved(u8),
+    /// A scheduled window started, or ended if `None`.
+    WindowChanged(Option<Window>),
+    /// Auto-tuning changed the maximum number of concurrent downloads.
+    ConcurrencyChanged(u8),
+    /// Progress of the whole pool, or of a job.
+    Stats(Option<String>, Stats),
+    /// A download was requested and is waiting for a worker.
+    Queued {
+        key: DownloadKey,
+        job: Option<String>,
+    },
+    /// A worker started on a download, or on the first segment of it.
+    Started {
+        key: DownloadKey,
+        job: Option<String>,
+    },
+    /// An attempt failed, the download is tried again after `delay`.
+    Retrying {
+        key: DownloadKey,
+        job: Option<String>,
+        attempt: u32,
+        delay: Duration,
+        error: DownloadError,
+    },
+    Failed {
+        key: DownloadKey,
+        job: Option<String>,
+        error: DownloadError,
+    },
+    /// Every handle of the download was cancelled, or the pool shut down.
+    Cancelled {
+        key: DownloadKey,
+        job: Option<String>,
+    },
+    Completed {
+        key: DownloadKey,
+        job: Option<String>,
+        output: Output,
+    },
+}
+
+impl Event {
+    /// Is the event only a snapshot that a newer one replaces, such as progress.
+    ///
+    /// Snapshots are dropped for a subscriber that falls behind, other events never are.
+    pub fn is_snapshot(&self) -> bool {
+        matches!(
+            self,
+            Self::WorkerUpdate(Update::Progress { .. }) | Self::Stats(..)
+        )
+    }
+}
+
+/// The subscribers of a pool.
+#[derive(Default)]
+pub(crate) struct Events {
+    subscribers: Mutex<Vec<Weak<Shared>>>,
+}
+
+impl Events {
+    pub fn subscribe(&self) -> Subscription {
+        let shared = Arc::new(Shared::default());
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&shared));
+        Subscription { shared }
+    }
+
+    pub fn send(&self, event: Event) {
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            let Some(subscriber) = subscriber.upgrade() else {
+                return false;
+            };
+            subscriber.push(event.clone());
+            true
+        });
+    }
+}
+
+impl Drop for Events {
+    fn drop(&mut self) {
+        for subscriber in self.subscribers.get_mut().unwrap().drain(..) {
+            if let Some(subscriber) = subscriber.upgrade() {
+                subscriber.queue.lock().unwrap().closed = true;
+                subscriber.notify.notify_one();
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<Event>,
+    /// Snapshots dropped because the subscriber fell behind.
+    missed: u64,
+    /// Lifecycle events dropped because the subscriber fell too far behind.
+    lagged: u64,
+    /// The pool is gone.
+    closed: bool,
+}
+
+impl Shared {
+    fn push(&self, event: Event) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.events.len() >= CAPACITY {
+            // Make room by dropping the oldest snapshot, or this one
+            if let Some(index) = queue.events.iter().position(Event::is_snapshot) {
+                queue.events.remove(index);
+                queue.missed += 1;
+            } else if event.is_snapshot() {
+                queue.missed += 1;
+                return;
+            } else if queue.events.len() >= MAX_EVENTS {
+                queue.events.pop_front();
+                queue.lagged += 1;
+            }
+        }
+        queue.events.push_back(event);
+        drop(queue);
+        self.notify.notify_one();
+    }
+}
+
+/// Receives the events of a pool in order.
+///
+/// A subscriber that falls behind misses snapshots such as progress and stats first, and
+/// only misses lifecycle events once it is thousands of events behind.
+pub struct Subscription {
+    shared: Arc<Shared>,
+}
+
+impl Subscription {
+    /// The next event, `None` once the pool is gone.
+    pub async fn recv(&mut self) -> Option<Event> {
+        loop {
+            let notified = self.shared.notify.notified();
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if let Some(event) = queue.events.pop_front() {
+                    return Some(event);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// The next event if there is one already.
+    pub fn try_recv(&mut self) -> Option<Event> {
+        self.shared.queue.lock().unwrap().events.pop_front()
+    }
+
+    /// The number of snapshots dropped because this subscriber fell behind.
+    pub fn missed(&self) -> u64 {
+        self.shared.queue.lock().unwrap().missed
+    }
+
+    /// The number of lifecycle events dropped because this subscriber fell too far behind.
+    ///
+    /// Once it isn't 0, state built from the events should be read from the pool again.
+    pub fn lagged(&self) -> u64 {
+        self.shared.queue.lock().unwrap().lagged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_events() {
+        let events = Events::default();
+        let mut subscription = events.subscribe();
+        let stats = || Event::Stats(None, Stats::default());
+        let key = DownloadKey::new("https://example.com/mod.pbo".to_string(), None);
+
+        events.send(Event::Queued {
+            key: key.clone(),
+            job: None,
+        });
+        for _ in 0..CAPACITY * 2 {
+            events.send(stats());
+        }
+        events.send(Event::Cancelled {
+            key: key.clone(),
+            job: None,
+        });
+        assert_eq!(subscription.missed(), CAPACITY as u64 + 2);
+
+        // Lifecycle events are kept, in order
+        assert!(matches!(
+            subscription.recv().await,
+            Some(Event::Queued { .. })
+        ));
+        let mut received = Vec::new();
+        while let Some(event) = subscription.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(received.len(), CAPACITY - 1);
+        assert!(matches!(received.last(), Some(Event::Cancelled { .. })));
+
+        // Over capacity with only lifecycle events, nothing is dropped
+        for _ in 0..CAPACITY * 2 {
+            events.send(Event::WorkerAdded(0));
+        }
+        events.send(stats());
+        assert_eq!(subscription.missed(), CAPACITY as u64 + 3);
+        assert_eq!(subscription.lagged(), 0);
+
+        // Until the subscriber is too far behind, then the oldest are dropped
+        for _ in 0..MAX_EVENTS {
+            events.send(Event::WorkerRemoved(0));
+        }
+        assert_eq!(subscription.lagged(), CAPACITY as u64 * 2);
+        assert!(matches!(
+            subscription.try_recv(),
+            Some(Event::WorkerRemoved(0))
+        ));
+        drop(events);
+        let mut count = 1;
+        while subscription.recv().await.is_some() {
+            count += 1;
+        }
+        assert_eq!(count, MAX_EVENTS);
+    }
+}
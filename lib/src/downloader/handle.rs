@@ -0,0 +1,95 @@
+use std::{future::IntoFuture, pin::Pin, sync::Arc};
+
+use tokio::sync::mpsc::{Receiver, WeakSender};
+
+use super::{
+    error::DownloadError,
+    pool::{Control, DownloadKey, Inner},
+    queue::Priority,
+    sink::Output,
+    worker::Update,
+};
+
+/// A download requested from a [`super::DownloadPool`].
+///
+/// Await the handle for the result, or use [`Self::recv`] to follow its progress.
+pub struct DownloadHandle {
+    key: DownloadKey,
+    updates: Receiver<Update>,
+    /// Identifies this subscriber to the pool, without keeping the channel open.
+    tx: WeakSender<Update>,
+    pool: Arc<Inner>,
+}
+
+impl DownloadHandle {
+    pub(crate) fn new(
+        key: DownloadKey,
+        updates: Receiver<Update>,
+        tx: WeakSender<Update>,
+        pool: Arc<Inner>,
+    ) -> Self {
+        Self {
+            key,
+            updates,
+            tx,
+            pool,
+        }
+    }
+
+    pub fn key(&self) -> &DownloadKey {
+        &self.key
+    }
+
+    /// The next update for this download, `None` once it has finished.
+    ///
+    /// Progress updates are dropped while the previous one hasn't been read yet.
+    pub async fn recv(&mut self) -> Option<Update> {
+        self.updates.recv().await
+    }
+
+    /// Wait for the download to finish.
+    pub async fn wait(mut self) -> Result<Output, DownloadError> {
+        while let Some(update) = self.updates.recv().await {
+            match update {
+                Update::Done(_, _, output) => return Ok(output),
+                Update::Failed(_, _, error) => return Err(error),
+                _ => {}
+            }
+        }
+        // The pool dropped the download without finishing it.
+        Err(DownloadError::Cancelled)
+    }
+
+    /// Stop waiting for the download.
+    ///
+    /// The download itself is cancelled if no other handle is waiting for it.
+    pub async fn cancel(self) {
+        if let Some(tx) = self.tx.upgrade() {
+            self.pool.unsubscribe(&self.key, &tx).await;
+        }
+    }
+
+    /// Pause the download, for every handle waiting for it.
+    pub async fn pause(&self) {
+        self.pool.control(&self.key, Control::Paused).await;
+    }
+
+    /// Resume the download after [`Self::pause`].
+    pub async fn resume(&self) {
+        self.pool.control(&self.key, Control::Running).await;
+    }
+
+    /// Change the priority of the download while it is pending.
+    pub async fn set_priority(&self, priority: Priority) -> bool {
+        self.pool.set_priority(&self.key, priority).await
+    }
+}
+
+impl IntoFuture for DownloadHandle {
+    type Output = Result<Output, DownloadError>;
+    type IntoFuture = Pin<Box<dyn std::future::Future<Output = Self::Output> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.wait())
+    }
+}
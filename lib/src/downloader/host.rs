@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use reqwest::{
+    header::{HeaderName, HeaderValue},
+    Client, RequestBuilder, Url,
+};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+
+use crate::repo::Password;
+
+/// Settings for each host, by host name or `host:port`.
+pub(crate) type Hosts = HashMap<String, HostConfig>;
+
+/// How to authenticate with a host.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Credentials {
+    /// HTTP basic auth.
+    Basic {
+        username: String,
+        password: Password,
+    },
+    /// A bearer token sent in the `Authorization` header.
+    Bearer(Password),
+    /// A signed query string such as `expires=...&signature=...`, appended to every URL.
+    Query(Password),
+}
+
+/// Credentials and extra headers sent with every request to a host, and the certificate it must present.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostConfig {
+    credentials: Option<Credentials>,
+    /// Header values may hold secrets too, such as API keys.
+    headers: Vec<(String, Password)>,
+    /// Hex encoded SHA-256 of the DER certificate the host must present.
+    pin: Option<String>,
+}
+
+impl HostConfig {
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Add a header, rejecting a name or value that can't be sent.
+    pub fn with_header(mut self, name: impl Into<String>, value: Password) -> Result<Self, String> {
+        let name = name.into();
+        header(&name, &value)?;
+        self.headers.push((name, value));
+        Ok(self)
+    }
+
+    /// Check every header can be sent, such as for a config loaded from a file.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in &self.headers {
+            header(name, value)?;
+        }
+        Ok(())
+    }
+
+    /// Only trust the host if it presents the certificate with this hex encoded SHA-256 fingerprint.
+    ///
+    /// Checked during the handshake instead of the usual checks, so a self-signed certificate
+    /// can be pinned and nothing is sent to a host presenting another one. Colons between bytes are allowed, as shown by most certificate viewers.
+    pub fn with_pin(mut self, fingerprint: &str) -> Self {
+        self.pin = Some(fingerprint.replace(':', "").to_ascii_lowercase());
+        self
+    }
+
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    pub fn headers(&self) -> &[(String, Password)] {
+        &self.headers
+    }
+
+    pub fn pin(&self) -> Option<&str> {
+        self.pin.as_deref()
+    }
+
+    /// Would a request redirected to another host carry secrets of this one.
+    pub(crate) fn is_private(&self) -> bool {
+        !self.headers.is_empty() || matches!(self.credentials, Some(Credentials::Query(_)))
+    }
+
+    /// Build a request for `url` with the credentials and headers.
+    pub(crate) fn get(&self, client: &Client, url: &str) -> RequestBuilder {
+        let mut req = match &self.credentials {
+            Some(Credentials::Query(signature)) => client.get(with_query(url, signature.reveal())),
+            _ => client.get(url),
+        };
+        match &self.credentials {
+            Some(Credentials::Basic { username, password }) => {
+                req = req.basic_auth(username, Some(password.reveal()));
+            }
+            Some(Credentials::Bearer(token)) => req = req.bearer_auth(token.reveal()),
+            Some(Credentials::Query(_)) | None => {}
+        }
+        // Checked when the config was set
+        for (name, value) in &self.headers {
+            if let Ok((name, value)) = header(name, value) {
+                req = req.header(name, value);
+            }
+        }
+        req
+    }
+}
+
+/// A header to send, its value marked sensitive so it is never logged.
+fn header(name: &str, value: &Password) -> Result<(HeaderName, HeaderValue), String> {
+    let name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("Invalid header name `{name}`"))?;
+    // The value is a secret, don't repeat it
+    let mut value = HeaderValue::from_str(value.reveal())
+        .map_err(|_| format!("Invalid value for header `{name}`"))?;
+    value.set_sensitive(true);
+    Ok((name, value))
+}
+
+/// The settings for the host of `url`, preferring an entry with the port.
+pub(crate) fn lookup<'a>(hosts: &'a Hosts, url: &str) -> Option<&'a HostConfig> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    url.port()
+        .and_then(|port| hosts.get(&format!("{host}:{port}")))
+        .or_else(|| hosts.get(host))
+}
+
+/// Hex encoded SHA-256 of a DER certificate, as pinned.
+pub(crate) fn fingerprint(der: &[u8]) -> String {
+    digest(&SHA256, der)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn with_query(url: &str, query: &str) -> String {
+    let Ok(mut url) = Url::parse(url) else {
+        return url.to_string();
+    };
+    let query = match url.query() {
+        Some(existing) if !existing.is_empty() => format!("{existing}&{query}"),
+        _ => query.to_string(),
+    };
+    url.set_query(Some(&query));
+    url.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::AUTHORIZATION;
+
+    use super::*;
+
+    #[test]
+    fn test_host() {
+        let mut hosts = Hosts::new();
+        hosts.insert(
+            "example.com".to_string(),
+            HostConfig::default()
+                .with_credentials(Credentials::Bearer(Password::new("token".to_string())))
+                .with_header("X-Unit", Password::new("unit".to_string()))
+                .unwrap(),
+        );
+        hosts.insert(
+            "example.com:8080".to_string(),
+            HostConfig::default()
+                .with_credentials(Credentials::Query(Password::new("sig=abc".to_string()))),
+        );
+        let client = Client::new();
+
+        let host = lookup(&hosts, "https://example.com/mod.pbo").unwrap();
+        let req = host
+            .get(&client, "https://example.com/mod.pbo")
+            .build()
+            .unwrap();
+        assert_eq!(req.headers()[AUTHORIZATION], "Bearer token");
+        assert!(req.headers()[AUTHORIZATION].is_sensitive());
+        assert_eq!(req.headers()["X-Unit"], "unit");
+        assert!(!format!("{host:?}").contains("token"));
+
+        let host = lookup(&hosts, "https://example.com:8080/mod.pbo?v=1").unwrap();
+        let req = host
+            .get(&client, "https://example.com:8080/mod.pbo?v=1")
+            .build()
+            .unwrap();
+        assert_eq!(req.url().query(), Some("v=1&sig=abc"));
+        assert!(!req.headers().contains_key(AUTHORIZATION));
+
+        assert!(lookup(&hosts, "https://example.org/mod.pbo").is_none());
+
+        let invalid = Password::new("key\nX-Injected: 1".to_string());
+        let error = HostConfig::default()
+            .with_header("X-Key", invalid)
+            .unwrap_err();
+        assert!(!error.contains("Injected"));
+        assert!(HostConfig::default()
+            .with_header("Bad Name", Password::new("value".to_string()))
+            .is_err());
+
+        let host = HostConfig::default().with_pin("AB:CD:EF");
+        assert_eq!(host.pin(), Some("abcdef"));
+        assert_eq!(fingerprint(b"").len(), 64);
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+
+/// A token bucket shared by all workers, limiting the total download speed.
+///
+/// The bucket holds up to one second of tokens, so short bursts are allowed.
+pub(crate) struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    /// Wakes waiting workers when the rate changes.
+    changed: Notify,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket::new(rate, Instant::now())),
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Change the rate, applied to waiting workers immediately.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        self.bucket.lock().unwrap().set_rate(rate, Instant::now());
+        self.changed.notify_waiters();
+    }
+
+    /// Take `bytes` tokens, waiting until the bucket is no longer in debt.
+    pub async fn acquire(&self, bytes: u64) {
+        let mut wait = self.bucket.lock().unwrap().take(bytes, Instant::now());
+        while let Some(delay) = wait {
+            let changed = self.changed.notified();
+            tokio::select! {
+                () = tokio::time::sleep(delay) => return,
+                () = changed => {}
+            }
+            wait = self.bucket.lock().unwrap().take(0, Instant::now());
+        }
+    }
+}
+
+struct Bucket {
+    /// Bytes per second, `None` for unlimited.
+    rate: Option<u64>,
+    /// Available tokens, negative while in debt.
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Option<u64>, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.unwrap_or(0) as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.last = now;
+    }
+
+    fn set_rate(&mut self, rate: Option<u64>, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.tokens = rate.map_or(0.0, |rate| self.tokens.min(rate as f64));
+    }
+
+    /// Take `bytes` tokens, returning how long to wait until the debt is paid off.
+    fn take(&mut self, bytes: u64, now: Instant) -> Option<Duration> {
+        let rate = self.rate?;
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            return None;
+        }
+        // A rate of 0 would never pay off, treat it as 1 byte per second.
+        Some(Duration::from_secs_f64(-self.tokens / rate.max(1) as f64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(Some(100), start);
+        // A full bucket allows a burst
+        assert_eq!(bucket.take(100, start), None);
+        assert_eq!(bucket.take(50, start), Some(Duration::from_millis(500)));
+        // Debt is paid off over time
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.take(0, later), Some(Duration::from_millis(250)));
+        // A higher rate pays off the debt sooner
+        bucket.set_rate(Some(250), later);
+        assert_eq!(bucket.take(0, later), Some(Duration::from_millis(100)));
+        // Tokens don't build up past one second
+        let later = later + Duration::from_secs(10);
+        assert_eq!(bucket.take(250, later), None);
+        assert_eq!(bucket.take(25, later), Some(Duration::from_millis(100)));
+        bucket.set_rate(None, later);
+        assert_eq!(bucket.take(1_000_000, later), None);
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Weight of the newest measurement in the moving averages.
+const ALPHA: f64 = 0.3;
+/// Bodies smaller than this finish too quickly to measure throughput.
+const MIN_MEASURED: u64 = 256 * 1024;
+/// How long a mirror is avoided after its first failure, doubled for each failure in a row.
+const BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// How well a mirror has served downloads.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Health {
+    /// Moving average of the time until the response started.
+    latency: Option<Duration>,
+    /// Moving average of the body throughput in bytes per second.
+    throughput: Option<f64>,
+    /// Failures since the last success.
+    errors: u32,
+    failed_at: Option<Instant>,
+}
+
+impl Health {
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    pub fn throughput(&self) -> Option<f64> {
+        self.throughput
+    }
+
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    /// Is the mirror avoided after failing recently.
+    pub fn is_down(&self) -> bool {
+        self.is_down_at(Instant::now())
+    }
+
+    fn is_down_at(&self, now: Instant) -> bool {
+        let Some(failed_at) = self.failed_at else {
+            return false;
+        };
+        let backoff = BACKOFF
+            .saturating_mul(1 << (self.errors.saturating_sub(1)).min(16))
+            .min(MAX_BACKOFF);
+        now.duration_since(failed_at) < backoff
+    }
+
+    /// Higher is better, mirrors without a measurement are scored as `unknown`.
+    fn score(&self, unknown: f64) -> f64 {
+        let throughput = self.throughput.unwrap_or(unknown);
+        let latency = self.latency.map_or(0.0, |l| l.as_secs_f64());
+        throughput / (1.0 + latency) / f64::from(1 << self.errors.min(16))
+    }
+
+    fn success(&mut self, latency: Duration, bytes: u64, elapsed: Duration) {
+        self.latency = Some(
+            self.latency
+                .map_or(latency, |l| l.mul_f64(1.0 - ALPHA) + latency.mul_f64(ALPHA)),
+        );
+        if bytes >= MIN_MEASURED && !elapsed.is_zero() {
+            let throughput = bytes as f64 / elapsed.as_secs_f64();
+            self.throughput = Some(
+                self.throughput
+                    .map_or(throughput, |t| t * (1.0 - ALPHA) + throughput * ALPHA),
+            );
+        }
+        self.errors = 0;
+        self.failed_at = None;
+    }
+
+    fn failure(&mut self, now: Instant) {
+        self.errors = self.errors.saturating_add(1);
+        self.failed_at = Some(now);
+    }
+}
+
+/// Groups of base URLs serving the same files, and the health of each.
+#[derive(Default)]
+pub(crate) struct Mirrors {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    groups: Vec<Vec<String>>,
+    health: HashMap<String, Health>,
+}
+
+impl State {
+    /// The group serving `url`, and the path of `url` below its base.
+    fn find<'a>(&self, url: &'a str) -> Option<(&[String], &'a str)> {
+        self.groups.iter().find_map(|group| {
+            group
+                .iter()
+                .find_map(|base| url.strip_prefix(base.as_str()))
+                .map(|path| (group.as_slice(), path))
+        })
+    }
+
+    fn base(&self, url: &str) -> Option<String> {
+        self.groups
+            .iter()
+            .flatten()
+            .find(|base| url.starts_with(base.as_str()))
+            .cloned()
+    }
+}
+
+impl Mirrors {
+    /// Serve URLs under any of `bases` from all of them, replacing groups that share a base.
+    pub fn add(&self, bases: Vec<String>) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .groups
+            .retain(|group| !group.iter().any(|base| bases.contains(base)));
+        state.groups.push(bases);
+    }
+
+    /// Remove the group containing `base`.
+    pub fn remove(&self, base: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let before = state.groups.len();
+        state
+            .groups
+            .retain(|group| !group.iter().any(|b| b == base));
+        let group_bases: Vec<String> = state.groups.iter().flatten().cloned().collect();
+        state.health.retain(|b, _| group_bases.contains(b));
+        state.groups.len() != before
+    }
+
+    pub fn health(&self, base: &str) -> Option<Health> {
+        self.state.lock().unwrap().health.get(base).copied()
+    }
+
+    /// The URL to fetch `url` from, preferring mirrors not in `tried`.
+    ///
+    /// Requests are spread across healthy mirrors in proportion to their score, mirrors that
+    /// failed recently are only used when every other mirror has too.
+    pub fn pick(&self, url: &str, tried: &[String]) -> String {
+        let state = self.state.lock().unwrap();
+        let Some((group, path)) = state.find(url) else {
+            return url.to_string();
+        };
+        let now = Instant::now();
+        let health = |base: &String| state.health.get(base).copied().unwrap_or_default();
+        // Mirrors without a measurement are scored as the average, so they get tried.
+        let measured: Vec<f64> = group
+            .iter()
+            .filter_map(|base| health(base).throughput)
+            .collect();
+        let unknown = if measured.is_empty() {
+            1.0
+        } else {
+            measured.iter().sum::<f64>() / measured.len() as f64
+        };
+
+        let untried: Vec<&String> = group
+            .iter()
+            .filter(|base| !tried.contains(&format!("{base}{path}")))
+            .collect();
+        let candidates = if untried.is_empty() {
+            group.iter().collect()
+        } else {
+            untried
+        };
+        let up: Vec<&String> = candidates
+            .iter()
+            .copied()
+            .filter(|base| !health(base).is_down_at(now))
+            .collect();
+        let base = if up.is_empty() {
+            // Everything is down, use the mirror that has failed the least.
+            candidates
+                .into_iter()
+                .min_by_key(|base| health(base).errors)
+                .unwrap()
+        } else {
+            let scores: Vec<f64> = up.iter().map(|base| health(base).score(unknown)).collect();
+            let mut choice = fastrand::f64() * scores.iter().sum::<f64>();
+            let mut picked = up[up.len() - 1];
+            for (base, score) in up.iter().zip(&scores) {
+                if choice < *score {
+                    picked = base;
+                    break;
+                }
+                choice -= score;
+            }
+            picked
+        };
+        format!("{base}{path}")
+    }
+
+    /// Are there mirrors for `url` that aren't in `tried`.
+    pub fn has_untried(&self, url: &str, tried: &[String]) -> bool {
+        let state = self.state.lock().unwrap();
+        state.find(url).is_some_and(|(group, path)| {
+            group
+                .iter()
+                .any(|base| !tried.contains(&format!("{base}{path}")))
+        })
+    }
+
+    /// Record a response from `url` that started after `latency`, and its body of `bytes` that took `elapsed`.
+    pub fn success(&self, url: &str, latency: Duration, bytes: u64, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(base) = state.base(url) {
+            state
+                .health
+                .entry(base)
+                .or_default()
+                .success(latency, bytes, elapsed);
+        }
+    }
+
+    /// Record a failed request to `url`, or a response with an incomplete or corrupt body.
+    pub fn failure(&self, url: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(base) = state.base(url) {
+            state
+                .health
+                .entry(base)
+                .or_default()
+                .failure(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mirrors() {
+        let mirrors = Mirrors::default();
+        mirrors.add(vec![
+            "https://a.example.com/repo/".to_string(),
+            "https://b.example.com/".to_string(),
+        ]);
+        let a = "https://a.example.com/repo/@mod/addons/mod.pbo";
+        let b = "https://b.example.com/@mod/addons/mod.pbo";
+
+        // URLs outside of any group are left alone
+        let other = "https://example.org/mod.pbo";
+        assert_eq!(mirrors.pick(other, &[]), other);
+        assert!(!mirrors.has_untried(other, &[]));
+
+        assert!([a, b].contains(&mirrors.pick(a, &[]).as_str()));
+        assert_eq!(mirrors.pick(a, &[a.to_string()]), b);
+        assert!(mirrors.has_untried(b, &[a.to_string()]));
+        assert!(!mirrors.has_untried(b, &[a.to_string(), b.to_string()]));
+
+        // A failed mirror is avoided while the other is up
+        mirrors.failure(a);
+        assert_eq!(
+            mirrors
+                .health("https://a.example.com/repo/")
+                .unwrap()
+                .errors(),
+            1
+        );
+        for _ in 0..20 {
+            assert_eq!(mirrors.pick(a, &[]), b);
+        }
+        // Unless it's the only one left to try
+        assert_eq!(mirrors.pick(a, &[b.to_string()]), a);
+
+        mirrors.success(
+            b,
+            Duration::from_millis(50),
+            1024 * 1024,
+            Duration::from_secs(1),
+        );
+        let health = mirrors.health("https://b.example.com/").unwrap();
+        assert_eq!(health.latency(), Some(Duration::from_millis(50)));
+        assert_eq!(health.throughput(), Some(1024.0 * 1024.0));
+
+        // A success clears the errors
+        mirrors.success(a, Duration::from_millis(50), 0, Duration::ZERO);
+        let health = mirrors.health("https://a.example.com/repo/").unwrap();
+        assert_eq!(health.errors(), 0);
+        assert!(!health.is_down());
+        assert_eq!(health.throughput(), None);
+
+        assert!(mirrors.remove("https://b.example.com/"));
+        assert_eq!(mirrors.pick(a, &[]), a);
+        assert!(mirrors.health("https://a.example.com/repo/").is_none());
+    }
+
+    #[test]
+    fn test_health() {
+        let now = Instant::now();
+        let mut health = Health::default();
+        health.success(
+            Duration::from_millis(100),
+            1024 * 1024,
+            Duration::from_secs(1),
+        );
+        let fast = health.score(1.0);
+        health.failure(now);
+        assert!(health.score(1.0) < fast);
+        assert!(health.is_down_at(now + Duration::from_secs(4)));
+        assert!(!health.is_down_at(now + Duration::from_secs(6)));
+        health.failure(now);
+        assert!(health.is_down_at(now + Duration::from_secs(6)));
+        assert!(!health.is_down_at(now + Duration::from_secs(11)));
+    }
+}
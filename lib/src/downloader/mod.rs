@@ -1,5 +1,41 @@
+mod client;
+mod decode;
+mod error;
+mod events;
+mod handle;
+mod host;
+mod limiter;
+mod mirror;
+mod partial;
+mod persist;
 mod pool;
+mod queue;
+mod retry;
+mod schedule;
+mod segment;
+mod sink;
+mod stall;
+mod stats;
+mod transport;
+mod tune;
+mod verify;
 mod worker;
 
-pub use pool::{DownloadKey, DownloadPool, Event};
+pub use chrono::Weekday;
+pub use client::{ClientConfig, Proxy};
+pub use error::{DownloadError, Timeout};
+pub use events::{Event, Subscription};
+pub use handle::DownloadHandle;
+pub use host::{Credentials, HostConfig};
+pub use mirror::Health;
+pub use pool::{DownloadKey, DownloadPool};
+pub use queue::{DownloadOptions, Priority};
+pub use retry::RetryPolicy;
+pub use schedule::{Schedule, Window};
+pub use sink::{Output, Sink};
+pub use stats::Stats;
+pub use transport::{Body, BoxFuture, FileTransport, Request, Response, Transport, TransportError};
+pub use tune::AutoTune;
 pub use worker::Update;
+
+pub(crate) use partial::Partial;
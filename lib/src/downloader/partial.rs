@@ -0,0 +1,168 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// A partially downloaded file, recorded next to it so it can be resumed after a restart.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Partial {
+    /// The URL the file is downloaded from.
+    url: String,
+    /// The ETag or Last-Modified value of the response, used as `If-Range` when resuming.
+    validator: Option<String>,
+    /// Bytes safely written to the partial file.
+    received: u64,
+}
+
+impl Partial {
+    pub fn new(url: String, validator: Option<String>, received: u64) -> Self {
+        Self {
+            url,
+            validator,
+            received,
+        }
+    }
+
+    /// The file the download is written to before it is complete.
+    pub fn part_path(path: &Path) -> PathBuf {
+        append(path, ".part")
+    }
+
+    /// The sidecar describing the partial file.
+    pub fn sidecar_path(path: &Path) -> PathBuf {
+        append(path, ".part.toml")
+    }
+
+    /// The sidecar describing the segment of the partial file starting at `offset`.
+    pub fn segment_path(path: &Path, offset: u64) -> PathBuf {
+        append(path, &format!(".seg{offset}"))
+    }
+
+    /// Load a partial download of `url` to `path` that can be resumed.
+    pub fn load(path: &Path, url: &str) -> Option<Self> {
+        Self::load_from(&Self::sidecar_path(path), &Self::part_path(path), 0, url)
+    }
+
+    /// Load a partial download of the segment of `url` starting at `offset`.
+    pub fn load_segment(path: &Path, offset: u64, url: &str) -> Option<Self> {
+        Self::load_from(
+            &Self::segment_path(path, offset),
+            &Self::part_path(path),
+            offset,
+            url,
+        )
+    }
+
+    fn load_from(sidecar: &Path, part: &Path, offset: u64, url: &str) -> Option<Self> {
+        Self::read(sidecar, part, offset).filter(|partial| partial.url == url)
+    }
+
+    /// Read a sidecar that could be resumed from, whatever its URL.
+    fn read(sidecar: &Path, part: &Path, offset: u64) -> Option<Self> {
+        let source = std::fs::read_to_string(sidecar).ok()?;
+        let partial: Self = toml::from_str(&source).ok()?;
+        let on_disk = std::fs::metadata(part).ok()?.len();
+        // Without a validator there is no way to know the file hasn't changed
+        if partial.validator.is_none()
+            || partial.received == 0
+            || on_disk < offset + partial.received
+        {
+            return None;
+        }
+        Some(partial)
+    }
+
+    /// Bytes that can be resumed from the partial file of `path`, whole or in segments.
+    ///
+    /// Segments are written in place, so the length of the partial file says nothing of
+    /// what was received.
+    pub fn resumable(path: &Path) -> u64 {
+        let part = Self::part_path(path);
+        let mut total = Self::read(&Self::sidecar_path(path), &part, 0).map_or(0, |p| p.received);
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return total;
+        };
+        let prefix = format!("{}.seg", name.to_string_lossy());
+        for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+            let Some(offset) = entry
+                .file_name()
+                .to_string_lossy()
+                .strip_prefix(&prefix)
+                .and_then(|offset| offset.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            total += Self::read(&entry.path(), &part, offset).map_or(0, |p| p.received);
+        }
+        total
+    }
+
+    /// Save to `sidecar`, the sidecar of the file or of one of its segments.
+    pub fn save(&self, sidecar: &Path) -> std::io::Result<()> {
+        let source = toml::to_string(self).map_err(std::io::Error::other)?;
+        std::fs::write(sidecar, source)
+    }
+
+    /// Remove any partial download of `path`.
+    pub fn discard(path: &Path) {
+        let _ = std::fs::remove_file(Self::part_path(path));
+        let _ = std::fs::remove_file(Self::sidecar_path(path));
+    }
+
+    pub fn validator(&self) -> Option<&str> {
+        self.validator.as_deref()
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn add_received(&mut self, bytes: u64) {
+        self.received += bytes;
+    }
+}
+
+fn append(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.pbo");
+        let url = "https://example.com/file.pbo";
+        Partial::new(url.to_string(), Some("\"etag\"".to_string()), 4)
+            .save(&Partial::sidecar_path(&path))
+            .unwrap();
+        // The partial file is shorter than recorded
+        std::fs::write(Partial::part_path(&path), b"abc").unwrap();
+        assert_eq!(Partial::load(&path, url), None);
+
+        std::fs::write(Partial::part_path(&path), b"abcde").unwrap();
+        assert_eq!(Partial::load(&path, "https://example.com/other"), None);
+        let partial = Partial::load(&path, url).unwrap();
+        assert_eq!(partial.received(), 4);
+        assert_eq!(partial.validator(), Some("\"etag\""));
+
+        assert_eq!(Partial::resumable(&path), 4);
+
+        // Segments count what they received, not the length of the sparse file
+        std::fs::write(Partial::part_path(&path), vec![0; 2000]).unwrap();
+        Partial::new(url.to_string(), Some("\"etag\"".to_string()), 100)
+            .save(&Partial::segment_path(&path, 1000))
+            .unwrap();
+        Partial::new(url.to_string(), None, 100)
+            .save(&Partial::segment_path(&path, 1500))
+            .unwrap();
+        assert_eq!(Partial::resumable(&path), 104);
+
+        Partial::discard(&path);
+        assert!(!Partial::part_path(&path).exists());
+        assert!(!Partial::sidecar_path(&path).exists());
+    }
+}
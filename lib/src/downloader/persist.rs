@@ -0,0 +1,120 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use super::{
+    pool::{DownloadKey, Inner},
+    queue::DownloadOptions,
+};
+
+/// Changes this close together are saved at once.
+const DELAY: Duration = Duration::from_millis(500);
+
+/// The downloads of a pool, saved so they can be requested again after a restart.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Saved {
+    #[serde(default)]
+    downloads: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    key: DownloadKey,
+    #[serde(default)]
+    options: DownloadOptions,
+}
+
+/// Load the downloads saved to `path`, none if nothing was saved yet.
+pub(crate) fn load(path: &Path) -> io::Result<Vec<(DownloadKey, DownloadOptions)>> {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let saved: Saved =
+        toml::from_str(&source).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(saved
+        .downloads
+        .into_iter()
+        .map(|entry| (entry.key, entry.options))
+        .collect())
+}
+
+/// Save `downloads` to `path`, only replacing it once written in full.
+pub(crate) fn save(path: &Path, downloads: Vec<(DownloadKey, DownloadOptions)>) -> io::Result<()> {
+    let saved = Saved {
+        downloads: downloads
+            .into_iter()
+            .map(|(key, options)| Entry { key, options })
+            .collect(),
+    };
+    let source = toml::to_string(&saved).map_err(io::Error::other)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    std::fs::write(&temp, source)?;
+    std::fs::rename(&temp, path)
+}
+
+/// Save the downloads of the pool to `path` as they change, until it shuts down or saves
+/// somewhere else.
+pub(crate) async fn run(inner: Weak<Inner>, path: PathBuf, changed: Arc<Notify>) {
+    loop {
+        changed.notified().await;
+        tokio::time::sleep(DELAY).await;
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        // The downloads are kept as they were when the pool shut down
+        if inner.is_shutdown() || inner.queue_file().as_deref() != Some(path.as_path()) {
+            return;
+        }
+        let downloads = inner.saved_downloads().await;
+        drop(inner);
+        let path = path.clone();
+        // Failing to save only loses progress since the last save, keep going
+        let _ = tokio::task::spawn_blocking(move || save(&path, downloads)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::{Priority, Sink};
+
+    #[test]
+    fn test_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("queue.toml");
+        assert!(load(&path).unwrap().is_empty());
+
+        let downloads = vec![
+            (
+                DownloadKey::new("https://example.com/mod.pbo".to_string(), None)
+                    .with_sink(Sink::File(dir.path().join("mod.pbo")))
+                    .with_size(1024)
+                    .with_sha256(vec![0xab; 32]),
+                DownloadOptions::default()
+                    .with_priority(Priority::High)
+                    .with_job("ace"),
+            ),
+            (
+                DownloadKey::new("https://example.com/mod.pbo".to_string(), Some((0, 99))),
+                DownloadOptions::default(),
+            ),
+        ];
+        save(&path, downloads.clone()).unwrap();
+        assert_eq!(load(&path).unwrap(), downloads);
+
+        std::fs::write(&path, "downloads = 1").unwrap();
+        assert_eq!(load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
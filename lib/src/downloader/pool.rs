@@ -1,30 +1,124 @@
 use std::{
+    collections::HashMap,
     hash::Hash,
+    path::PathBuf,
     sync::{
         atomic::{AtomicU64, AtomicU8},
-        Arc,
+        Arc, Weak,
     },
 };
 
-use tokio::sync::{
-    mpsc::{Receiver, Sender},
-    RwLock,
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc::Sender, watch, Mutex, Notify, RwLock},
+    task::JoinSet,
 };
 
-use super::worker::{Command, Update, Worker};
+use crate::repo::{Encoding, File};
+
+use super::{
+    client::ClientConfig,
+    error::DownloadError,
+    events::{Event, Events, Subscription},
+    handle::DownloadHandle,
+    host::{HostConfig, Hosts},
+    limiter::RateLimiter,
+    mirror::{Health, Mirrors},
+    persist,
+    queue::{DownloadOptions, Priority, Queue},
+    retry::RetryPolicy,
+    schedule::{self, Schedule, Window},
+    segment,
+    sink::Sink,
+    stats::{self, Stats, Tracker},
+    transport::{self, Transport, Transports},
+    tune::{self, AutoTune, Meter},
+    worker::{Command, Update, Worker},
+};
 
 type Workers = RwLock<Vec<(u8, Sender<Command>)>>;
-type Pending = RwLock<Vec<(DownloadKey, Sender<Update>)>>;
+type Pending = RwLock<Queue>;
 type Subscribers = RwLock<Vec<(DownloadKey, Sender<Update>)>>;
+type Controls = RwLock<HashMap<DownloadKey, watch::Sender<Control>>>;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Downloads of at least twice this size are split into segments by default.
+const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct DownloadKey {
     url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     range: Option<(u64, u64)>,
+    #[serde(default)]
+    sink: Sink,
+    /// The expected size of the download, the range if there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// The expected SHA-256 of the download.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<Vec<u8>>,
+    /// The compressed copy to fetch instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<Encoding>,
 }
 impl DownloadKey {
+    /// A download kept in memory.
     pub fn new(url: String, range: Option<(u64, u64)>) -> Self {
-        Self { url, range }
+        Self {
+            url,
+            range,
+            sink: Sink::Memory,
+            size: None,
+            sha256: None,
+            encoding: None,
+        }
+    }
+
+    /// A download of a repository file from `url`, checked against its size and hash.
+    ///
+    /// Fetches the smallest compressed copy in one of the `accepted` encodings, if the file
+    /// has one. A PBO from a repository that didn't publish the hash of its bytes is only
+    /// checked by its size.
+    pub fn file(url: String, file: &File, accepted: &[Encoding]) -> Self {
+        let mut key = Self::new(url, None).with_size(file.size());
+        if !file.sha256().is_empty() {
+            key = key.with_sha256(file.sha256().to_vec());
+        }
+        if let Some(variant) = file.variant(accepted) {
+            key = key.with_encoding(variant.encoding());
+        }
+        key
+    }
+
+    /// Write the download somewhere other than memory.
+    pub fn with_sink(mut self, sink: Sink) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    /// Fail the attempt unless exactly `size` bytes are received.
+    ///
+    /// A whole download of a known size can also be split into segments.
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Fail the attempt unless the bytes received hash to `sha256`.
+    pub fn with_sha256(mut self, sha256: Vec<u8>) -> Self {
+        self.sha256 = Some(sha256);
+        self
+    }
+
+    /// Fetch the copy compressed with `encoding` instead, at the URL with its extension, and
+    /// decompress it as it streams in.
+    ///
+    /// The expected size and hash are of the decompressed bytes. Compressed downloads start
+    /// over rather than resume, and aren't split into segments or used for ranges.
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = Some(encoding);
+        self
     }
 
     pub fn url(&self) -> &str {
@@ -34,29 +128,107 @@ impl DownloadKey {
     pub fn range(&self) -> Option<(u64, u64)> {
         self.range
     }
+
+    pub fn sink(&self) -> &Sink {
+        &self.sink
+    }
+
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    pub fn sha256(&self) -> Option<&[u8]> {
+        self.sha256.as_deref()
+    }
+
+    /// The compression the download is fetched with, never for a range.
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.encoding.filter(|_| self.range.is_none())
+    }
+}
+
+/// Requested state of a single download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Control {
+    Running,
+    Paused,
+    Cancelled,
+}
+
+/// State of the whole pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PoolState {
+    Running,
+    Paused,
+    /// Active downloads stop, keeping partial files so they can be resumed later.
+    Shutdown,
 }
 
 pub struct DownloadPool {
+    inner: Arc<Inner>,
+}
+
+pub(crate) struct Inner {
     /// The maximum number of concurrent downloads.
     max_concurrent: AtomicU8,
     /// The current number of concurrent downloads.
-    current_concurrent: Arc<AtomicU8>,
+    current_concurrent: AtomicU8,
+    /// Limits for tuning `max_concurrent` to the connection.
+    auto_tune: watch::Sender<Option<AutoTune>>,
+    /// Throughput, errors and latency of the workers, for auto-tuning.
+    meter: Arc<Meter>,
+
+    /// Limits the total download speed of all workers.
+    limiter: Arc<RateLimiter>,
+    /// The rate limit outside of scheduled windows, 0 for unlimited.
+    rate_limit: AtomicU64,
+    /// Rate limits by time of day.
+    schedule: watch::Sender<Option<Schedule>>,
+    /// The scheduled window currently applied.
+    window: std::sync::Mutex<Option<Window>>,
+
+    /// The HTTP client shared by all workers.
+    client: watch::Sender<Client>,
+    /// The settings of the client, workers apply its timeouts to transfers.
+    client_config: watch::Sender<ClientConfig>,
+    /// Credentials and headers for each host.
+    hosts: watch::Sender<Hosts>,
+    /// Transports by URL scheme.
+    transports: watch::Sender<Transports>,
+    /// Base URLs serving the same files, and their health.
+    mirrors: Arc<Mirrors>,
 
-    /// The maximum total download speed in bytes per second.
-    rate_limit: Arc<AtomicU64>,
-    rate_limit_watch: Arc<tokio::sync::watch::Sender<Option<u64>>>,
+    /// How failed downloads are retried.
+    retry_watch: watch::Sender<RetryPolicy>,
+
+    /// The size of the segments large downloads are split into, 0 to never split.
+    segment_size: AtomicU64,
+
+    /// Whether the pool is running, paused or shutting down.
+    state: watch::Sender<PoolState>,
 
     /// The current worker threads.
-    workers: Arc<Workers>,
+    workers: Workers,
+    /// The tasks running the workers.
+    tasks: Mutex<JoinSet<()>>,
 
     /// Pending downloads, waiting for a free worker.
-    pending: Arc<Pending>,
+    pending: Pending,
+
+    /// Requested state of each pending or active download.
+    controls: Controls,
+    /// Progress of the downloads requested from the pool, by job.
+    tracker: Tracker,
+    /// Where the downloads requested from the pool are saved, if anywhere.
+    queue_file: std::sync::Mutex<Option<PathBuf>>,
+    /// Notified when the downloads to save change.
+    queue_changed: Arc<Notify>,
 
-    /// Subscribers to the updates.
-    broadcast: tokio::sync::broadcast::Sender<Event>,
+    /// Subscribers to the events of the pool.
+    events: Events,
 
     /// Subscribers to specific downloads.
-    subscribers: Arc<Subscribers>,
+    subscribers: Subscribers,
 
     global: Sender<Update>,
 }
@@ -66,204 +238,709 @@ impl DownloadPool {
     // Async just to be able to use tokio::spawn.
     pub async fn new(max_concurrent: u8, rate_limit: Option<u64>) -> Self {
         let (tx, mut rx) = tokio::sync::mpsc::channel::<Update>(10);
-        let current_concurrent = Arc::new(AtomicU8::new(0));
-        let pending: Arc<Pending> = Arc::new(RwLock::new(Vec::new()));
-        let workers: Arc<Workers> = Arc::new(RwLock::new(Vec::new()));
-        let broadcast = tokio::sync::broadcast::channel(10).0;
-        let subscribers: Arc<Subscribers> = Arc::new(RwLock::new(Vec::new()));
-        let rate_limit_watch = Arc::new(tokio::sync::watch::channel(rate_limit).0);
-        let rate_limit = Arc::new(AtomicU64::new(rate_limit.unwrap_or(0)));
+        let client = watch::channel(ClientConfig::default().build().unwrap()).0;
+        let client_config = watch::channel(ClientConfig::default()).0;
+        let hosts = watch::channel(Hosts::new()).0;
+        let transports = transport::defaults(
+            client.subscribe(),
+            client_config.subscribe(),
+            hosts.subscribe(),
+        );
+        let inner = Arc::new(Inner {
+            max_concurrent: AtomicU8::new(max_concurrent),
+            current_concurrent: AtomicU8::new(0),
+            auto_tune: watch::channel(None).0,
+            meter: Arc::new(Meter::default()),
+            limiter: Arc::new(RateLimiter::new(rate_limit)),
+            rate_limit: AtomicU64::new(rate_limit.unwrap_or(0)),
+            schedule: watch::channel(None).0,
+            window: std::sync::Mutex::new(None),
+            client,
+            client_config,
+            hosts,
+            transports: watch::channel(transports).0,
+            mirrors: Arc::new(Mirrors::default()),
+            retry_watch: watch::channel(RetryPolicy::default()).0,
+            segment_size: AtomicU64::new(DEFAULT_SEGMENT_SIZE),
+            state: watch::channel(PoolState::Running).0,
+            workers: RwLock::new(Vec::new()),
+            tasks: Mutex::new(JoinSet::new()),
+            pending: RwLock::new(Queue::default()),
+            controls: RwLock::new(HashMap::new()),
+            tracker: Tracker::default(),
+            queue_file: std::sync::Mutex::new(None),
+            queue_changed: Arc::new(Notify::new()),
+            events: Events::default(),
+            subscribers: RwLock::new(Vec::new()),
+            global: tx,
+        });
         {
-            let current_concurrent = current_concurrent.clone();
-            let pending = pending.clone();
-            let workers = workers.clone();
-            let broadcast = broadcast.clone();
-            let subscribers = subscribers.clone();
-            let rate_limit_watch = rate_limit_watch.clone();
-            let rate_limit = rate_limit.clone();
+            // Weak, so dropping the pool stops the loop.
+            let inner = Arc::downgrade(&inner);
             tokio::spawn(async move {
-                loop {
-                    let update = rx.recv().await.unwrap();
-                    let _ = broadcast.send(Event::WorkerUpdate(update.clone()));
-                    for (key, tx) in subscribers.read().await.iter() {
-                        if key == update.key() {
-                            let _ = tx.send(update.clone()).await;
-                        }
-                    }
-                    if let Update::Done(id, _, _) = update {
-                        // If there are pending downloads, send one to the response worker.
-                        if let Some((key, tx)) = { pending.write().await.pop() } {
-                            // Register the subscriber.
-                            subscribers.write().await.push((key.clone(), tx.clone()));
-                            // Send the download to the worker.
-                            workers
-                                .read()
-                                .await
-                                .iter()
-                                .find(|(wid, _)| *wid == id)
-                                .unwrap()
-                                .1
-                                .send(Command::Download(key))
-                                .await
-                                .unwrap();
-                        } else {
-                            // Otherwise, remove the worker.
-                            workers
-                                .read()
-                                .await
-                                .iter()
-                                .find(|(wid, _)| *wid == id)
-                                .unwrap()
-                                .1
-                                .send(Command::Stop)
-                                .await
-                                .unwrap();
-                            workers.write().await.retain(|(wid, _)| *wid != id);
-                            current_concurrent.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
-                            Self::workers_set_rate_limit(
-                                &rate_limit_watch,
-                                &rate_limit,
-                                &current_concurrent,
-                            )
-                            .await;
-                            let _ = broadcast.send(Event::WorkerRemoved(id));
-                        }
-                    }
+                while let Some(update) = rx.recv().await {
+                    let Some(inner) = Weak::upgrade(&inner) else {
+                        break;
+                    };
+                    inner.handle_update(update).await;
                 }
             });
         }
-        Self {
-            max_concurrent: AtomicU8::new(max_concurrent),
-            current_concurrent,
-            rate_limit,
-            rate_limit_watch,
-            workers,
-            pending,
-            broadcast,
-            subscribers,
-            global: tx,
-        }
+        tokio::spawn(schedule::run(
+            Arc::downgrade(&inner),
+            inner.schedule.subscribe(),
+        ));
+        tokio::spawn(tune::run(
+            Arc::downgrade(&inner),
+            inner.auto_tune.subscribe(),
+        ));
+        tokio::spawn(stats::run(Arc::downgrade(&inner)));
+        Self { inner }
     }
 
-    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<Event> {
-        self.broadcast.subscribe()
+    /// Receive the events of the pool, from now on.
+    pub fn subscribe(&self) -> Subscription {
+        self.inner.events.subscribe()
     }
 
-    pub fn set_max_concurrent(&self, max_concurrent: u8) {
-        self.max_concurrent
+    pub async fn set_max_concurrent(&self, max_concurrent: u8) {
+        self.inner
+            .max_concurrent
             .store(max_concurrent, std::sync::atomic::Ordering::Relaxed);
+        self.inner.dispatch().await;
+    }
+
+    /// Tune the number of concurrent downloads within the limits of `tune`, or stop tuning.
+    ///
+    /// Workers are added while they raise the total throughput, and removed on errors or latency
+    /// spikes, starting from the current [`Self::max_concurrent`]. [`Event::ConcurrencyChanged`]
+    /// is sent for every change.
+    pub fn set_auto_tune(&self, tune: Option<AutoTune>) {
+        self.inner.auto_tune.send_replace(tune);
+    }
+
+    pub fn auto_tune(&self) -> Option<AutoTune> {
+        *self.inner.auto_tune.borrow()
     }
 
     pub fn max_concurrent(&self) -> u8 {
-        self.max_concurrent
+        self.inner
+            .max_concurrent
             .load(std::sync::atomic::Ordering::Relaxed)
     }
 
     pub fn current_concurrent(&self) -> u8 {
-        self.current_concurrent
+        self.inner
+            .current_concurrent
             .load(std::sync::atomic::Ordering::Relaxed)
     }
 
+    /// Set the maximum total download speed in bytes per second, applied immediately.
+    ///
+    /// While a scheduled window is active, its rate limit applies instead.
     pub async fn set_rate_limit(&self, rate_limit: Option<u64>) {
-        self.rate_limit.store(
+        self.inner.rate_limit.store(
             rate_limit.unwrap_or(0),
             std::sync::atomic::Ordering::Relaxed,
         );
-        Self::workers_set_rate_limit(
-            &self.rate_limit_watch,
-            &self.rate_limit,
-            &self.current_concurrent,
-        )
-        .await;
+        self.inner.apply_rate_limit();
     }
 
+    /// The rate limit currently applied, from the active window or [`Self::set_rate_limit`].
     pub fn rate_limit(&self) -> Option<u64> {
-        if self.rate_limit.load(std::sync::atomic::Ordering::Relaxed) == 0 {
+        self.inner.limiter.rate()
+    }
+
+    /// Change the rate limit by time of day, [`Event::WindowChanged`] is sent when the active window changes.
+    pub fn set_schedule(&self, schedule: Option<Schedule>) {
+        self.inner.schedule.send_replace(schedule);
+    }
+
+    pub fn schedule(&self) -> Option<Schedule> {
+        self.inner.schedule.borrow().clone()
+    }
+
+    /// The scheduled window currently active.
+    pub fn window(&self) -> Option<Window> {
+        self.inner.window.lock().unwrap().clone()
+    }
+
+    /// Replace the HTTP client shared by the workers, applies to attempts that haven't started yet.
+    pub fn set_client_config(&self, config: ClientConfig) -> reqwest::Result<()> {
+        self.inner.client.send_replace(config.build()?);
+        self.inner.client_config.send_replace(config);
+        Ok(())
+    }
+
+    pub fn client_config(&self) -> ClientConfig {
+        self.inner.client_config.borrow().clone()
+    }
+
+    /// Send credentials and extra headers with every request to `host`, a host name or `host:port`.
+    ///
+    /// Fails if a header can't be sent, rather than have the host turn the requests down.
+    pub fn set_host(&self, host: impl Into<String>, config: HostConfig) -> Result<(), String> {
+        config.validate()?;
+        let host = host.into();
+        self.inner.hosts.send_modify(|hosts| {
+            hosts.insert(host, config);
+        });
+        Ok(())
+    }
+
+    pub fn remove_host(&self, host: &str) -> Option<HostConfig> {
+        let mut removed = None;
+        self.inner
+            .hosts
+            .send_modify(|hosts| removed = hosts.remove(host));
+        removed
+    }
+
+    pub fn host(&self, host: &str) -> Option<HostConfig> {
+        self.inner.hosts.borrow().get(host).cloned()
+    }
+
+    /// Fetch URLs with `scheme`, such as `https` or `file`, through `transport`.
+    pub fn set_transport(&self, scheme: &str, transport: Arc<dyn Transport>) {
+        let scheme = scheme.to_ascii_lowercase();
+        self.inner.transports.send_modify(|transports| {
+            transports.insert(scheme, transport);
+        });
+    }
+
+    /// Download URLs under any of the base URLs in `mirrors` from all of them, such as the
+    /// mirrors listed by a repository's unit.
+    ///
+    /// Requests are spread across the mirrors by their health, and fail over to another mirror
+    /// when one fails or sends an incomplete file. Replaces any mirrors sharing a base URL.
+    pub fn add_mirrors(&self, mirrors: Vec<String>) {
+        self.inner.mirrors.add(mirrors);
+    }
+
+    /// Stop using the mirrors grouped with `base`.
+    pub fn remove_mirrors(&self, base: &str) -> bool {
+        self.inner.mirrors.remove(base)
+    }
+
+    /// The health of the mirror at `base`, once it has been used.
+    pub fn mirror_health(&self, base: &str) -> Option<Health> {
+        self.inner.mirrors.health(base)
+    }
+
+    /// Set how failed downloads are retried, applies to attempts that haven't started yet.
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        self.inner.retry_watch.send_replace(policy);
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        *self.inner.retry_watch.borrow()
+    }
+
+    /// Download `key`, or join the download if it is already pending or active.
+    pub async fn download(&self, key: DownloadKey) -> DownloadHandle {
+        self.download_with(key, DownloadOptions::default()).await
+    }
+
+    /// Download `key` with a priority or as part of a job.
+    ///
+    /// Joining a pending download raises it to the higher of the two priorities.
+    pub async fn download_with(
+        &self,
+        key: DownloadKey,
+        options: DownloadOptions,
+    ) -> DownloadHandle {
+        if !self.inner.is_shutdown() && self.inner.tracker.track(&key, &options) {
+            self.inner.events.send(Event::Queued {
+                key: key.clone(),
+                job: options.job().map(String::from),
+            });
+            self.inner.queue_changed.notify_one();
+        }
+        self.inner.download(key, options).await
+    }
+
+    /// Save the downloads requested from the pool to `path` as they change, and request the
+    /// downloads saved there by an earlier run again.
+    ///
+    /// Downloads are saved until they finish, those still pending or active at
+    /// [`Self::shutdown`] are kept. Downloads to files resume from their partial files.
+    /// Returns handles to the downloads that were requested again.
+    pub async fn persist(&self, path: impl Into<PathBuf>) -> std::io::Result<Vec<DownloadHandle>> {
+        let path = path.into();
+        let load = path.clone();
+        let saved = tokio::task::spawn_blocking(move || persist::load(&load))
+            .await
+            .map_err(std::io::Error::other)??;
+        *self.inner.queue_file.lock().unwrap() = Some(path.clone());
+        let mut handles = Vec::with_capacity(saved.len());
+        for (key, options) in saved {
+            handles.push(self.download_with(key, options).await);
+        }
+        // Fail now if the file can't be written, rather than on the next change
+        let downloads = self.inner.saved_downloads().await;
+        let save = path.clone();
+        tokio::task::spawn_blocking(move || persist::save(&save, downloads))
+            .await
+            .map_err(std::io::Error::other)??;
+        tokio::spawn(persist::run(
+            Arc::downgrade(&self.inner),
+            path,
+            self.inner.queue_changed.clone(),
+        ));
+        Ok(handles)
+    }
+
+    /// Progress of all downloads since the pool was last idle, also sent every second as
+    /// [`Event::Stats`] while there are downloads.
+    pub fn stats(&self) -> Stats {
+        self.inner.tracker.stats()
+    }
+
+    /// Progress of the downloads of `job`, until its last download finishes.
+    pub fn job_stats(&self, job: &str) -> Option<Stats> {
+        self.inner.tracker.job(job)
+    }
+
+    /// Split downloads of a known size into segments of `segment_size` bytes, downloaded in parallel.
+    ///
+    /// Only downloads of at least two segments are split, `None` never splits downloads.
+    pub fn set_segment_size(&self, segment_size: Option<u64>) {
+        self.inner.segment_size.store(
+            segment_size.unwrap_or(0),
+            std::sync::atomic::Ordering::Relaxed,
+        );
+    }
+
+    pub fn segment_size(&self) -> Option<u64> {
+        match self
+            .inner
+            .segment_size
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            0 => None,
+            size => Some(size),
+        }
+    }
+
+    /// Change the priority of a pending download.
+    ///
+    /// Returns false if the download isn't pending, it may already be active.
+    pub async fn set_priority(&self, key: &DownloadKey, priority: Priority) -> bool {
+        self.inner.set_priority(key, priority).await
+    }
+
+    /// Change the priority of every pending download of `job`.
+    pub async fn set_job_priority(&self, job: &str, priority: Priority) {
+        self.inner
+            .pending
+            .write()
+            .await
+            .set_job_priority(job, priority);
+        self.inner.queue_changed.notify_one();
+    }
+
+    /// Pause all downloads, active downloads hold on to their worker.
+    pub fn pause_all(&self) {
+        self.inner.state.send_if_modified(|state| {
+            let running = *state == PoolState::Running;
+            if running {
+                *state = PoolState::Paused;
+            }
+            running
+        });
+    }
+
+    /// Resume all downloads after [`Self::pause_all`].
+    pub async fn resume_all(&self) {
+        self.inner.state.send_if_modified(|state| {
+            let paused = *state == PoolState::Paused;
+            if paused {
+                *state = PoolState::Running;
+            }
+            paused
+        });
+        self.inner.dispatch().await;
+    }
+
+    /// Stop all downloads and wait for the workers to exit.
+    ///
+    /// Pending downloads are dropped, and partial files of active downloads are kept so they can be resumed.
+    /// With [`Self::persist`], both are saved to be requested again on the next run.
+    pub async fn shutdown(self) {
+        self.inner.state.send_replace(PoolState::Shutdown);
+        // Save the downloads before they are dropped, so the next run picks them up
+        if let Some(path) = self.inner.queue_file() {
+            let downloads = self.inner.saved_downloads().await;
+            let _ = tokio::task::spawn_blocking(move || persist::save(&path, downloads)).await;
+        }
+        {
+            let mut controls = self.inner.controls.write().await;
+            let mut subscribers = self.inner.subscribers.write().await;
+            for key in self.inner.pending.write().await.drain() {
+                controls.remove(&key);
+                if let Some(job) = self.inner.tracker.forget(&key) {
+                    self.inner.events.send(Event::Cancelled {
+                        key: key.clone(),
+                        job,
+                    });
+                }
+                // Dropping the sender ends the handle as cancelled.
+                subscribers.retain(|(k, _)| *k != key);
+            }
+        }
+        let mut tasks = std::mem::take(&mut *self.inner.tasks.lock().await);
+        while tasks.join_next().await.is_some() {}
+    }
+}
+
+impl Inner {
+    /// Forward an update from a worker, and give the worker its next download once done.
+    async fn handle_update(&self, update: Update) {
+        self.events.send(Event::WorkerUpdate(update.clone()));
+        if let Update::Retrying { error, .. } | Update::Failed(_, _, error) = &update {
+            if error.is_retryable() {
+                self.meter.error();
+            }
+        }
+        if !update.is_finished() {
+            self.progress(update).await;
+            return;
+        }
+
+        let id = update.id();
+        self.finish(update).await;
+
+        // If there are pending downloads, send one to the free worker, unless there are too many.
+        let excess = self.workers.read().await.len()
+            > self
+                .max_concurrent
+                .load(std::sync::atomic::Ordering::Relaxed) as usize;
+        let next = if excess {
             None
         } else {
-            Some(self.rate_limit.load(std::sync::atomic::Ordering::Relaxed))
+            self.next_pending().await
+        };
+        if let Some((key, control)) = next {
+            if let Some((_, tx)) = self.workers.read().await.iter().find(|(wid, _)| *wid == id) {
+                let _ = tx.send(Command::Download(key, control)).await;
+                return;
+            }
+        }
+        // Otherwise, remove the worker.
+        let mut workers = self.workers.write().await;
+        if let Some(position) = workers.iter().position(|(wid, _)| *wid == id) {
+            let (_, tx) = workers.remove(position);
+            let _ = tx.send(Command::Stop).await;
+            self.current_concurrent
+                .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+            drop(workers);
+            self.events.send(Event::WorkerRemoved(id));
         }
     }
 
-    pub async fn download(&self, key: DownloadKey) -> Receiver<Update> {
+    /// Queue a download, or join it if it is already pending or active.
+    pub(crate) async fn download(
+        self: &Arc<Self>,
+        key: DownloadKey,
+        options: DownloadOptions,
+    ) -> DownloadHandle {
         let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let handle = DownloadHandle::new(key.clone(), rx, tx.downgrade(), self.clone());
+        if self.is_shutdown() {
+            // Dropping the sender ends the handle as cancelled.
+            return handle;
+        }
+
+        let mut controls = self.controls.write().await;
+        self.subscribers.write().await.push((key.clone(), tx));
+        // If the key is already pending or active, the new subscriber joins it.
+        if let Some(control) = controls.get(&key) {
+            // Rescue a download whose last subscriber just cancelled it.
+            control.send_if_modified(|c| {
+                let cancelled = *c == Control::Cancelled;
+                if cancelled {
+                    *c = Control::Running;
+                }
+                cancelled
+            });
+            self.pending
+                .write()
+                .await
+                .raise_priority(&key, options.priority());
+            return handle;
+        }
+        let control = watch::channel(Control::Running).0;
+        let segment_size = self.segment_size.load(std::sync::atomic::Ordering::Relaxed);
+        if let Some(ranges) = segment::split(&key, options.size().or(key.size()), segment_size) {
+            let control_rx = control.subscribe();
+            controls.insert(key.clone(), control);
+            drop(controls);
+            tokio::spawn(segment::download(
+                self.clone(),
+                key,
+                options,
+                ranges,
+                control_rx,
+            ));
+            return handle;
+        }
+        controls.insert(key.clone(), control);
+        self.pending.write().await.push(key, options);
+        drop(controls);
+
+        self.dispatch().await;
+        handle
+    }
 
-        // If the URL is already in the subscribers list, add a new subscriber and return.
+    /// Queue a download already requested, to be downloaded in one stream.
+    pub(crate) async fn requeue(&self, key: DownloadKey, options: DownloadOptions) {
+        self.pending.write().await.push(key, options);
+        self.dispatch().await;
+    }
+
+    /// Send an update that isn't final to the subscribers of its download.
+    pub(crate) async fn progress(&self, update: Update) {
+        if let Some(job) = self.tracker.update(&update) {
+            let key = update.key().clone();
+            match &update {
+                Update::Started { .. } => self.events.send(Event::Started { key, job }),
+                Update::Retrying {
+                    attempt,
+                    delay,
+                    error,
+                    ..
+                } => self.events.send(Event::Retrying {
+                    key,
+                    job,
+                    attempt: *attempt,
+                    delay: *delay,
+                    error: error.clone(),
+                }),
+                Update::Progress { .. } | Update::Done(..) | Update::Failed(..) => {}
+            }
+        }
+        for (key, tx) in self.subscribers.read().await.iter() {
+            if key == update.key() {
+                // Progress is only a snapshot, a full subscriber can skip it.
+                let _ = tx.try_send(update.clone());
+            }
+        }
+    }
+
+    /// Send the final update of a download to its subscribers, and forget the download.
+    pub(crate) async fn finish(&self, update: Update) {
+        if let Some(job) = self.tracker.finish(&update) {
+            self.queue_changed.notify_one();
+            let key = update.key().clone();
+            match &update {
+                Update::Done(_, _, output) => self.events.send(Event::Completed {
+                    key,
+                    job,
+                    output: output.clone(),
+                }),
+                Update::Failed(_, _, DownloadError::Cancelled) => {
+                    self.events.send(Event::Cancelled { key, job });
+                }
+                Update::Failed(_, _, error) => self.events.send(Event::Failed {
+                    key,
+                    job,
+                    error: error.clone(),
+                }),
+                Update::Started { .. } | Update::Progress { .. } | Update::Retrying { .. } => {}
+            }
+        }
+        let mut controls = self.controls.write().await;
+        controls.remove(update.key());
+        self.pending.write().await.finished(update.key());
+        // Later downloads of the same key start over.
         let mut subscribers = self.subscribers.write().await;
-        for (key2, _) in subscribers.iter() {
-            if key == *key2 {
-                subscribers.push((key.clone(), tx.clone()));
-                return rx;
+        let mut finished = Vec::new();
+        subscribers.retain(|(key, tx)| {
+            if key == update.key() {
+                finished.push(tx.clone());
+                false
+            } else {
+                true
             }
+        });
+        for tx in finished {
+            let update = update.clone();
+            // Don't hold up the pool on a subscriber that isn't reading.
+            tokio::spawn(async move {
+                let _ = tx.send(update).await;
+            });
         }
+    }
 
-        // Create a new worker if we are not at the maximum number of concurrent downloads.
-        let mut workers = self.workers.write().await;
-        if workers.len()
-            < self
-                .max_concurrent
-                .load(std::sync::atomic::Ordering::Relaxed) as usize
-        {
+    /// Apply a new scheduled window, or the pool's own rate limit outside of a window.
+    pub(crate) fn set_window(&self, window: Option<Window>) {
+        *self.window.lock().unwrap() = window.clone();
+        self.apply_rate_limit();
+        self.events.send(Event::WindowChanged(window));
+    }
+
+    fn apply_rate_limit(&self) {
+        let rate_limit = self.window.lock().unwrap().as_ref().map_or_else(
+            || match self.rate_limit.load(std::sync::atomic::Ordering::Relaxed) {
+                0 => None,
+                limit => Some(limit),
+            },
+            Window::rate_limit,
+        );
+        self.limiter.set_rate(rate_limit);
+    }
+
+    pub(crate) fn tracker(&self) -> &Tracker {
+        &self.tracker
+    }
+
+    pub(crate) fn send_event(&self, event: Event) {
+        self.events.send(event);
+    }
+
+    pub(crate) fn meter(&self) -> &Meter {
+        &self.meter
+    }
+
+    /// The maximum number of concurrent downloads, and whether every worker is busy.
+    pub(crate) fn concurrency(&self) -> (u8, bool) {
+        let max = self
+            .max_concurrent
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let current = self
+            .current_concurrent
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let running = *self.state.borrow() == PoolState::Running;
+        (max, running && current >= max)
+    }
+
+    /// Change the maximum number of concurrent downloads as tuned.
+    pub(crate) async fn set_concurrency(&self, max_concurrent: u8) {
+        self.max_concurrent
+            .store(max_concurrent, std::sync::atomic::Ordering::Relaxed);
+        self.events.send(Event::ConcurrencyChanged(max_concurrent));
+        self.dispatch().await;
+    }
+
+    pub(crate) fn is_shutdown(&self) -> bool {
+        *self.state.borrow() == PoolState::Shutdown
+    }
+
+    /// Take the next pending download that is not paused, if the pool is running.
+    async fn next_pending(&self) -> Option<(DownloadKey, watch::Receiver<Control>)> {
+        if *self.state.borrow() != PoolState::Running {
+            return None;
+        }
+        let controls = self.controls.read().await;
+        let mut pending = self.pending.write().await;
+        let key = pending.pop(|key| {
+            controls
+                .get(key)
+                .is_some_and(|c| *c.borrow() == Control::Running)
+        })?;
+        let control = controls.get(&key)?.subscribe();
+        Some((key, control))
+    }
+
+    /// Start new workers for pending downloads, up to the maximum.
+    pub(crate) async fn dispatch(&self) {
+        loop {
+            let mut workers = self.workers.write().await;
+            if workers.len()
+                >= self
+                    .max_concurrent
+                    .load(std::sync::atomic::Ordering::Relaxed) as usize
+            {
+                return;
+            }
+            let Some((key, control)) = self.next_pending().await else {
+                return;
+            };
             let id = (0..=u8::MAX)
                 .find(|id| !workers.iter().any(|(id2, _)| id == id2))
                 .unwrap();
             let (command_tx, command_rx) = tokio::sync::mpsc::channel(3);
-            let update_tx = self.global.clone();
-            let mut worker =
-                Worker::new(id, update_tx, command_rx, self.rate_limit_watch.subscribe()).unwrap();
-            tokio::spawn(async move {
+            let mut worker = Worker::new(
+                id,
+                self.global.clone(),
+                command_rx,
+                self.transports.subscribe(),
+                self.client_config.subscribe(),
+                self.limiter.clone(),
+                self.mirrors.clone(),
+                self.meter.clone(),
+                self.retry_watch.subscribe(),
+                self.state.subscribe(),
+            );
+            self.tasks.lock().await.spawn(async move {
                 worker.run().await;
             });
+            // Announce the worker before any of its updates.
+            self.events.send(Event::WorkerAdded(id));
+            let _ = command_tx.send(Command::Download(key, control)).await;
             workers.push((id, command_tx));
-            subscribers.push((key.clone(), tx.clone()));
-            workers
-                .iter()
-                .find(|(wid, _)| *wid == id)
-                .unwrap()
-                .1
-                .send(Command::Download(key))
-                .await
-                .unwrap();
             self.current_concurrent
                 .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            drop(workers);
-            Self::workers_set_rate_limit(
-                &self.rate_limit_watch,
-                &self.rate_limit,
-                &self.current_concurrent,
-            )
-            .await;
-            let _ = self.broadcast.send(Event::WorkerAdded(id));
-        } else {
-            // Otherwise, add the download to the pending list.
-            self.pending.write().await.push((key, tx.clone()));
         }
+    }
 
-        rx
+    /// Change the priority of a pending download.
+    pub(crate) async fn set_priority(&self, key: &DownloadKey, priority: Priority) -> bool {
+        let changed = self.pending.write().await.set_priority(key, priority);
+        if changed {
+            self.queue_changed.notify_one();
+        }
+        changed
     }
 
-    async fn workers_set_rate_limit(
-        rate_limit_watch: &tokio::sync::watch::Sender<Option<u64>>,
-        rate_limit: &AtomicU64,
-        current_concurrent: &AtomicU8,
-    ) -> Option<u64> {
-        let max = rate_limit.load(std::sync::atomic::Ordering::Relaxed);
-        let current = current_concurrent.load(std::sync::atomic::Ordering::Relaxed) as u64;
-        let max = if max == 0 {
-            None
-        } else if current == 0 {
-            Some(max)
-        } else {
-            Some(max / current)
-        };
-        rate_limit_watch.send(max).unwrap();
-        max
+    pub(crate) fn queue_file(&self) -> Option<PathBuf> {
+        self.queue_file.lock().unwrap().clone()
+    }
+
+    /// The downloads requested from the pool to save, pending ones with their current priority.
+    pub(crate) async fn saved_downloads(&self) -> Vec<(DownloadKey, DownloadOptions)> {
+        let pending = self.pending.read().await;
+        self.tracker
+            .downloads()
+            .into_iter()
+            .map(|(key, options)| {
+                let options = pending.options(&key).cloned().unwrap_or(options);
+                (key, options)
+            })
+            .collect()
+    }
+
+    /// Set the state of a pending or active download.
+    pub(crate) async fn control(&self, key: &DownloadKey, state: Control) {
+        if let Some(control) = self.controls.read().await.get(key) {
+            control.send_replace(state);
+        }
+        if state == Control::Running {
+            self.dispatch().await;
+        }
     }
-}
 
-#[derive(Debug, Clone)]
-pub enum Event {
-    WorkerUpdate(Update),
-    WorkerAdded(u8),
-    WorkerRemoved(u8),
+    /// Remove a subscriber, cancelling the download if nobody else is waiting for it.
+    pub(crate) async fn unsubscribe(&self, key: &DownloadKey, tx: &Sender<Update>) {
+        let mut controls = self.controls.write().await;
+        let mut subscribers = self.subscribers.write().await;
+        subscribers.retain(|(k, s)| k != key || !s.same_channel(tx));
+        if subscribers.iter().any(|(k, _)| k == key) {
+            return;
+        }
+        let mut pending = self.pending.write().await;
+        if pending.remove(key) {
+            controls.remove(key);
+            if let Some(job) = self.tracker.forget(key) {
+                self.queue_changed.notify_one();
+                self.events.send(Event::Cancelled {
+                    key: key.clone(),
+                    job,
+                });
+            }
+        } else if let Some(control) = controls.get(key) {
+            // The worker reports back once it has stopped.
+            control.send_replace(Control::Cancelled);
+        }
+    }
 }
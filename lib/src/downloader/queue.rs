@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use super::pool::DownloadKey;
+
+/// How urgently a download is needed, higher priorities are started first.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// How a download is scheduled.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DownloadOptions {
+    priority: Priority,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+impl DownloadOptions {
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Group the download with others, such as the files of a mod sync.
+    ///
+    /// Jobs of the same priority share the workers, and so the bandwidth, evenly.
+    pub fn with_job(mut self, job: impl Into<String>) -> Self {
+        self.job = Some(job.into());
+        self
+    }
+
+    /// The size of the download, if known, lets a large download be split into segments.
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn job(&self) -> Option<&str> {
+        self.job.as_deref()
+    }
+
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    key: DownloadKey,
+    options: DownloadOptions,
+}
+
+/// The queued downloads of one job, by the order they were queued in.
+type Job = BTreeMap<u64, Entry>;
+
+/// Downloads waiting for a worker.
+///
+/// Ordered by priority, then shared between jobs, then first in first out.
+#[derive(Debug, Default)]
+pub(crate) struct Queue {
+    /// Each job with queued downloads, by priority.
+    jobs: BTreeMap<Priority, HashMap<Option<String>, Job>>,
+    /// Where each queued download is, its priority, job and order.
+    index: HashMap<DownloadKey, (Priority, Option<String>, u64)>,
+    next_seq: u64,
+    /// The job of each active download.
+    active: HashMap<DownloadKey, Option<String>>,
+}
+
+impl Queue {
+    pub fn push(&mut self, key: DownloadKey, options: DownloadOptions) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let (priority, job) = (options.priority, options.job.clone());
+        self.index.insert(key.clone(), (priority, job.clone(), seq));
+        self.jobs
+            .entry(priority)
+            .or_default()
+            .entry(job)
+            .or_default()
+            .insert(seq, Entry { key, options });
+    }
+
+    /// Remove a queued download.
+    pub fn remove(&mut self, key: &DownloadKey) -> bool {
+        self.take(key).is_some()
+    }
+
+    /// Take a queued download out of its job.
+    fn take(&mut self, key: &DownloadKey) -> Option<(u64, Entry)> {
+        let (priority, job, seq) = self.index.remove(key)?;
+        let jobs = self.jobs.get_mut(&priority)?;
+        let entries = jobs.get_mut(&job)?;
+        let entry = entries.remove(&seq)?;
+        if entries.is_empty() {
+            jobs.remove(&job);
+            if jobs.is_empty() {
+                self.jobs.remove(&priority);
+            }
+        }
+        Some((seq, entry))
+    }
+
+    /// Remove all queued downloads, in the order they were queued.
+    pub fn drain(&mut self) -> Vec<DownloadKey> {
+        self.index.clear();
+        let mut entries = std::mem::take(&mut self.jobs)
+            .into_values()
+            .flat_map(HashMap::into_values)
+            .flatten()
+            .collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|(seq, _)| *seq);
+        entries.into_iter().map(|(_, e)| e.key).collect()
+    }
+
+    /// Change the priority of a queued download.
+    pub fn set_priority(&mut self, key: &DownloadKey, priority: Priority) -> bool {
+        let Some((seq, mut entry)) = self.take(key) else {
+            return false;
+        };
+        entry.options.priority = priority;
+        let job = entry.options.job.clone();
+        self.index.insert(key.clone(), (priority, job.clone(), seq));
+        self.jobs
+            .entry(priority)
+            .or_default()
+            .entry(job)
+            .or_default()
+            .insert(seq, entry);
+        true
+    }
+
+    /// The options of a queued download.
+    pub fn options(&self, key: &DownloadKey) -> Option<&DownloadOptions> {
+        let (priority, job, seq) = self.index.get(key)?;
+        self.jobs
+            .get(priority)?
+            .get(job)?
+            .get(seq)
+            .map(|e| &e.options)
+    }
+
+    /// Raise the priority of a queued download, used when another caller joins it.
+    pub fn raise_priority(&mut self, key: &DownloadKey, priority: Priority) {
+        if let Some((queued, _, _)) = self.index.get(key) {
+            if *queued < priority {
+                self.set_priority(key, priority);
+            }
+        }
+    }
+
+    /// Change the priority of every queued download of `job`.
+    pub fn set_job_priority(&mut self, job: &str, priority: Priority) {
+        let job = Some(job.to_string());
+        let mut moved = Job::new();
+        self.jobs.retain(|_, jobs| {
+            if let Some(entries) = jobs.remove(&job) {
+                moved.extend(entries);
+            }
+            !jobs.is_empty()
+        });
+        if moved.is_empty() {
+            return;
+        }
+        for (seq, entry) in &mut moved {
+            entry.options.priority = priority;
+            self.index
+                .insert(entry.key.clone(), (priority, job.clone(), *seq));
+        }
+        self.jobs
+            .entry(priority)
+            .or_default()
+            .entry(job)
+            .or_default()
+            .extend(moved);
+    }
+
+    /// Take the next download that `ready` accepts, and mark it active.
+    pub fn pop(&mut self, ready: impl Fn(&DownloadKey) -> bool) -> Option<DownloadKey> {
+        let mut running: HashMap<Option<&str>, usize> = HashMap::new();
+        for job in self.active.values() {
+            *running.entry(job.as_deref()).or_default() += 1;
+        }
+        // Highest priority, then the job with the fewest active downloads, then the oldest.
+        let key = self.jobs.values().rev().find_map(|jobs| {
+            jobs.iter()
+                .filter_map(|(job, entries)| {
+                    let (seq, entry) = entries.iter().find(|(_, e)| ready(&e.key))?;
+                    let running = running.get(&job.as_deref()).copied().unwrap_or_default();
+                    Some(((running, *seq), &entry.key))
+                })
+                .min_by_key(|(order, _)| *order)
+                .map(|(_, key)| key.clone())
+        })?;
+        let (_, entry) = self.take(&key)?;
+        self.active.insert(entry.key.clone(), entry.options.job);
+        Some(entry.key)
+    }
+
+    /// A download taken by [`Self::pop`] has finished.
+    pub fn finished(&mut self, key: &DownloadKey) {
+        self.active.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> DownloadKey {
+        DownloadKey::new(format!("https://example.com/{name}"), None)
+    }
+
+    fn pop(queue: &mut Queue) -> Option<String> {
+        queue.pop(|_| true).map(|k| {
+            k.url()
+                .trim_start_matches("https://example.com/")
+                .to_string()
+        })
+    }
+
+    #[test]
+    fn test_queue() {
+        let mut queue = Queue::default();
+        queue.push(key("a"), DownloadOptions::default());
+        queue.push(key("b"), DownloadOptions::default());
+        queue.push(
+            key("c"),
+            DownloadOptions::default().with_priority(Priority::High),
+        );
+        queue.push(key("d"), DownloadOptions::default());
+        assert!(queue.set_priority(&key("d"), Priority::High));
+        assert_eq!(pop(&mut queue).as_deref(), Some("c"));
+        assert_eq!(pop(&mut queue).as_deref(), Some("d"));
+        assert_eq!(pop(&mut queue).as_deref(), Some("a"));
+        assert_eq!(pop(&mut queue).as_deref(), Some("b"));
+        assert_eq!(pop(&mut queue), None);
+    }
+
+    #[test]
+    fn test_fair() {
+        let mut queue = Queue::default();
+        for name in ["a1", "a2", "a3"] {
+            queue.push(key(name), DownloadOptions::default().with_job("a"));
+        }
+        for name in ["b1", "b2"] {
+            queue.push(key(name), DownloadOptions::default().with_job("b"));
+        }
+        assert_eq!(pop(&mut queue).as_deref(), Some("a1"));
+        assert_eq!(pop(&mut queue).as_deref(), Some("b1"));
+        assert_eq!(pop(&mut queue).as_deref(), Some("a2"));
+        // b has fewer active downloads once b1 is done
+        queue.finished(&key("b1"));
+        assert_eq!(pop(&mut queue).as_deref(), Some("b2"));
+        queue.set_job_priority("a", Priority::Low);
+        queue.push(key("c"), DownloadOptions::default());
+        assert_eq!(pop(&mut queue).as_deref(), Some("c"));
+        assert_eq!(pop(&mut queue).as_deref(), Some("a3"));
+        assert_eq!(pop(&mut queue), None);
+    }
+
+    #[test]
+    fn test_large() {
+        let mut queue = Queue::default();
+        for i in 0..20_000 {
+            let job = format!("job{}", i % 4);
+            queue.push(
+                key(&i.to_string()),
+                DownloadOptions::default().with_job(job),
+            );
+        }
+        assert!(queue.remove(&key("3")));
+        queue.raise_priority(&key("19999"), Priority::High);
+        assert_eq!(pop(&mut queue).as_deref(), Some("19999"));
+        let mut popped = 1;
+        while let Some(name) = pop(&mut queue) {
+            queue.finished(&key(&name));
+            popped += 1;
+        }
+        assert_eq!(popped, 19_999);
+        assert!(queue.drain().is_empty());
+    }
+}
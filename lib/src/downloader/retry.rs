@@ -0,0 +1,107 @@
+use std::time::{Duration, SystemTime};
+
+use reqwest::{header::RETRY_AFTER, Response};
+
+/// How often and how long to wait before retrying a failed download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The total number of attempts, including the first.
+    max_attempts: u32,
+    /// The delay before the first retry, doubled for each retry after.
+    base_delay: Duration,
+    /// The longest delay between two attempts.
+    max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub const fn new(max_attempts: u32, base_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            max_attempts,
+            base_delay,
+            max_delay,
+        }
+    }
+
+    /// Never retry a failed download.
+    pub const fn none() -> Self {
+        Self::new(1, Duration::ZERO, Duration::ZERO)
+    }
+
+    pub const fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub const fn base_delay(&self) -> Duration {
+        self.base_delay
+    }
+
+    pub const fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+
+    /// The delay after a failed `attempt`, starting at 1.
+    ///
+    /// Exponential backoff with equal jitter, so retries from many workers don't line up.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let half = backoff / 2;
+        half + half.mul_f64(fastrand::f64())
+    }
+
+    /// The delay after a failed `attempt`, or the one the server asked for.
+    ///
+    /// The server can't park a worker for longer than the longest delay of the policy.
+    pub fn retry_delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        retry_after.map_or_else(|| self.delay(attempt), |delay| delay.min(self.max_delay))
+    }
+}
+
+/// The delay requested by a 429 or 503 response.
+pub(crate) fn retry_after(response: &Response) -> Option<Duration> {
+    if !matches!(response.status().as_u16(), 429 | 503) {
+        return None;
+    }
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    httpdate::parse_http_date(value)
+        .ok()?
+        .duration_since(SystemTime::now())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::new(10, Duration::from_secs(1), Duration::from_secs(10));
+        for (attempt, backoff) in [(1, 1), (2, 2), (3, 4), (4, 8), (5, 10), (9, 10)] {
+            let delay = policy.delay(attempt);
+            let backoff = Duration::from_secs(backoff);
+            assert!(
+                delay >= backoff / 2 && delay <= backoff,
+                "{attempt}: {delay:?}"
+            );
+        }
+        let day = Some(Duration::from_secs(86400));
+        assert_eq!(policy.retry_delay(1, day), Duration::from_secs(10));
+        let second = Some(Duration::from_secs(1));
+        assert_eq!(policy.retry_delay(5, second), Duration::from_secs(1));
+    }
+}
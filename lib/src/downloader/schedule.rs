@@ -0,0 +1,162 @@
+use std::{sync::Weak, time::Duration};
+
+use chrono::{Datelike, Local, Timelike, Weekday};
+use tokio::sync::watch;
+
+use super::pool::Inner;
+
+/// How often the schedule is checked for a new window.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A rate limit applied on some days during some hours, in local time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    days: Vec<Weekday>,
+    /// The hour the window starts, from 0 to 23.
+    start: u32,
+    /// The hour the window ends, exclusive, wrapping past midnight if not after `start`.
+    end: u32,
+    rate_limit: Option<u64>,
+}
+
+impl Window {
+    /// A window from `start` to `end` hours on each of `days`.
+    ///
+    /// An `end` before `start` continues past midnight into the next day, an
+    /// equal `start` and `end` lasts 24 hours.
+    pub fn new(
+        days: impl IntoIterator<Item = Weekday>,
+        start: u32,
+        end: u32,
+        rate_limit: Option<u64>,
+    ) -> Self {
+        Self {
+            days: days.into_iter().collect(),
+            start: start % 24,
+            end: end % 24,
+            rate_limit,
+        }
+    }
+
+    /// A window from `start` to `end` hours on every day.
+    pub fn daily(start: u32, end: u32, rate_limit: Option<u64>) -> Self {
+        Self::new(
+            [
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+                Weekday::Sat,
+                Weekday::Sun,
+            ],
+            start,
+            end,
+            rate_limit,
+        )
+    }
+
+    pub fn days(&self) -> &[Weekday] {
+        &self.days
+    }
+
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    pub fn rate_limit(&self) -> Option<u64> {
+        self.rate_limit
+    }
+
+    /// Is the window active during `hour` of `day`.
+    pub fn contains(&self, day: Weekday, hour: u32) -> bool {
+        if self.start < self.end {
+            self.days.contains(&day) && (self.start..self.end).contains(&hour)
+        } else {
+            (self.days.contains(&day) && hour >= self.start)
+                || (self.days.contains(&day.pred()) && hour < self.end)
+        }
+    }
+}
+
+/// Rate limits that change with the time of day.
+///
+/// Outside of every window, the pool's own rate limit applies.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    /// A schedule of `windows`, where earlier windows take precedence when they overlap.
+    pub fn new(windows: Vec<Window>) -> Self {
+        Self { windows }
+    }
+
+    pub fn windows(&self) -> &[Window] {
+        &self.windows
+    }
+
+    /// The window active during `hour` of `day`.
+    pub fn window_at(&self, day: Weekday, hour: u32) -> Option<&Window> {
+        self.windows.iter().find(|w| w.contains(day, hour))
+    }
+
+    /// The window active now.
+    pub fn window(&self) -> Option<&Window> {
+        let now = Local::now();
+        self.window_at(now.weekday(), now.hour())
+    }
+}
+
+/// Apply the active window of the schedule to the pool, until the pool is dropped.
+pub(crate) async fn run(inner: Weak<Inner>, mut schedule: watch::Receiver<Option<Schedule>>) {
+    let mut active = None;
+    loop {
+        let window = schedule
+            .borrow_and_update()
+            .as_ref()
+            .and_then(Schedule::window)
+            .cloned();
+        if window != active {
+            let Some(inner) = inner.upgrade() else {
+                return;
+            };
+            inner.set_window(window.clone());
+            active = window;
+        }
+        tokio::select! {
+            () = tokio::time::sleep(CHECK_INTERVAL) => {}
+            changed = schedule.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let evening = Window::new([Weekday::Fri, Weekday::Sat], 18, 2, Some(1024));
+        let overnight = Window::daily(0, 8, None);
+        let schedule = Schedule::new(vec![evening.clone(), overnight.clone()]);
+        assert_eq!(schedule.window_at(Weekday::Fri, 17), None);
+        assert_eq!(schedule.window_at(Weekday::Fri, 18), Some(&evening));
+        assert_eq!(schedule.window_at(Weekday::Sat, 1), Some(&evening));
+        assert_eq!(schedule.window_at(Weekday::Sat, 2), Some(&overnight));
+        assert_eq!(schedule.window_at(Weekday::Sun, 23), None);
+        assert_eq!(schedule.window_at(Weekday::Sun, 1), Some(&evening));
+        assert_eq!(schedule.window_at(Weekday::Mon, 1), Some(&overnight));
+        assert_eq!(schedule.window_at(Weekday::Tue, 8), None);
+        assert!(Window::daily(6, 6, None).contains(Weekday::Wed, 12));
+    }
+}
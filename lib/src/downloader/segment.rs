@@ -0,0 +1,315 @@
+use std::{
+    future::Future,
+    io::{Read, Write},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::sync::{mpsc, watch};
+
+use super::{
+    error::DownloadError,
+    handle::DownloadHandle,
+    partial::Partial,
+    pool::{Control, DownloadKey, Inner},
+    queue::DownloadOptions,
+    sink::{Output, Sink},
+    verify::Verifier,
+    worker::Update,
+};
+
+/// Split a whole download of `size` bytes into inclusive ranges of `segment_size` bytes.
+///
+/// Only downloads of at least two segments are split.
+pub(crate) fn split(
+    key: &DownloadKey,
+    size: Option<u64>,
+    segment_size: u64,
+) -> Option<Vec<(u64, u64)>> {
+    let size = size?;
+    if key.range().is_some()
+        || key.encoding().is_some()
+        || segment_size == 0
+        || size < segment_size.saturating_mul(2)
+    {
+        return None;
+    }
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start < size {
+        let end = start.saturating_add(segment_size).min(size);
+        ranges.push((start, end - 1));
+        start = end;
+    }
+    Some(ranges)
+}
+
+/// Download the segments of `key` on separate workers, each written in place into one
+/// partial file.
+///
+/// Each segment is its own download, retried and resumed on its own. If the whole doesn't
+/// match the key, the file is downloaded again in one stream.
+// Boxed, as the segments are queued through `Inner::download` which spawns this.
+pub(crate) fn download(
+    inner: Arc<Inner>,
+    key: DownloadKey,
+    options: DownloadOptions,
+    ranges: Vec<(u64, u64)>,
+    control: watch::Receiver<Control>,
+) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async move {
+        let path = match key.sink() {
+            Sink::File(path) => {
+                // Progress of a download in one stream doesn't apply to the segments
+                let _ = std::fs::remove_file(Partial::sidecar_path(path));
+                path.clone()
+            }
+            Sink::Memory | Sink::Hash | Sink::Segment { .. } => {
+                std::env::temp_dir().join(format!("hermes-{:016x}", fastrand::u64(..)))
+            }
+        };
+        let (tx, mut rx) = mpsc::channel(ranges.len());
+        let mut segments = Vec::with_capacity(ranges.len());
+        let mut progress = vec![(0, 0.0); ranges.len()];
+        let mut done = 0;
+        for (index, range) in ranges.iter().enumerate() {
+            let size = range.1 - range.0 + 1;
+            let segment = DownloadKey::new(key.url().to_string(), Some(*range))
+                .with_sink(Sink::Segment {
+                    path: path.clone(),
+                    offset: range.0,
+                })
+                .with_size(size);
+            segments.push(segment.clone());
+            // A segment finished before a shutdown isn't downloaded again
+            if segment
+                .sink()
+                .partial(key.url())
+                .is_some_and(|p| p.received() >= size)
+            {
+                progress[index] = (size, 0.0);
+                done += 1;
+                continue;
+            }
+            let handle = inner.download(segment, options.clone()).await;
+            tokio::spawn(follow(index, handle, control.clone(), tx.clone()));
+        }
+        drop(tx);
+
+        let total = ranges.last().map_or(0, |(_, end)| end + 1);
+        let mut last_id = 0;
+        let mut error = None;
+        let mut started = false;
+        while let Some((index, update)) = rx.recv().await {
+            last_id = update.id();
+            match update {
+                // The download started with its first segment
+                Update::Started { id, .. } if !started => {
+                    started = true;
+                    inner
+                        .progress(Update::Started {
+                            id,
+                            key: key.clone(),
+                        })
+                        .await;
+                }
+                Update::Started { .. } => {}
+                Update::Progress {
+                    id,
+                    downloaded,
+                    speed,
+                    ..
+                } => {
+                    progress[index] = (downloaded, speed);
+                    inner
+                        .progress(Update::Progress {
+                            id,
+                            key: key.clone(),
+                            downloaded: progress.iter().map(|(d, _)| d).sum(),
+                            total,
+                            speed: progress.iter().map(|(_, s)| s).sum(),
+                        })
+                        .await;
+                }
+                Update::Retrying {
+                    id,
+                    attempt,
+                    delay,
+                    error,
+                    ..
+                } => {
+                    inner
+                        .progress(Update::Retrying {
+                            id,
+                            key: key.clone(),
+                            attempt,
+                            delay,
+                            error,
+                        })
+                        .await;
+                }
+                Update::Done(..) => {
+                    let (start, end) = ranges[index];
+                    progress[index] = (end - start + 1, 0.0);
+                    done += 1;
+                }
+                Update::Failed(_, _, e) => {
+                    if error.is_none() {
+                        error = Some(e);
+                        // Stop the other segments, the download can't complete. On shutdown
+                        // they stop on their own, keeping what they downloaded.
+                        if !inner.is_shutdown() {
+                            inner.control(&key, Control::Cancelled).await;
+                        }
+                    }
+                }
+            }
+        }
+
+        let update = if error.is_none() && done == segments.len() {
+            let whole = key.clone();
+            let part = path.clone();
+            match tokio::task::spawn_blocking(move || complete(&whole, &part, total)).await {
+                Ok(Ok(output)) => Update::Done(last_id, key.clone(), output),
+                Ok(Err(error @ DownloadError::Verification(_))) => {
+                    // Which segment is wrong can't be told, download the file again in one
+                    // stream, retried and failed over like any other download.
+                    discard(&segments, &path);
+                    inner
+                        .progress(Update::Retrying {
+                            id: last_id,
+                            key: key.clone(),
+                            attempt: 1,
+                            delay: Duration::ZERO,
+                            error,
+                        })
+                        .await;
+                    inner.requeue(key, options).await;
+                    return;
+                }
+                Ok(Err(e)) => Update::Failed(last_id, key.clone(), e),
+                Err(e) => Update::Failed(last_id, key.clone(), DownloadError::Io(e.to_string())),
+            }
+        } else {
+            Update::Failed(
+                last_id,
+                key.clone(),
+                error.unwrap_or(DownloadError::Cancelled),
+            )
+        };
+
+        // Segments of a file are kept on shutdown, so they can be resumed.
+        let keep = inner.is_shutdown()
+            && matches!(key.sink(), Sink::File(_))
+            && matches!(update, Update::Failed(..));
+        if !keep {
+            discard(&segments, &path);
+        }
+        inner.finish(update).await;
+    })
+}
+
+/// Remove the progress of the segments, and the partial file they were written to.
+fn discard(segments: &[DownloadKey], path: &Path) {
+    for segment in segments {
+        segment.sink().discard();
+    }
+    Partial::discard(path);
+}
+
+/// Verify the `total` bytes of the segments written to the partial file of `path`, and
+/// produce the output of `key`.
+fn complete(key: &DownloadKey, path: &Path, total: u64) -> Result<Output, DownloadError> {
+    let io = |e: std::io::Error| DownloadError::Io(e.to_string());
+    let part = Partial::part_path(path);
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&part)
+        .map_err(io)?;
+    // Drop anything left past the end by an earlier, longer version of the file
+    file.set_len(total).map_err(io)?;
+    // A file is moved into place, other sinks are given its bytes
+    let mut writer = match key.sink() {
+        Sink::File(_) => None,
+        sink => Some(sink.open(key.url(), None, 0).map_err(io)?),
+    };
+    let mut verifier = Verifier::new(key);
+    let mut buf = vec![0; 256 * 1024];
+    loop {
+        let read = file.read(&mut buf).map_err(io)?;
+        if read == 0 {
+            break;
+        }
+        verifier.update(&buf[..read]);
+        if let Some(writer) = &mut writer {
+            writer.write_all(&buf[..read]).map_err(io)?;
+        }
+    }
+    verifier.verify()?;
+    drop(file);
+    match writer {
+        Some(writer) => writer.finish().map_err(io),
+        None => {
+            std::fs::rename(&part, path).map_err(io)?;
+            Ok(Output::File(path.to_path_buf()))
+        }
+    }
+}
+
+/// Forward the updates of a segment, and apply the state of the whole download to it.
+async fn follow(
+    index: usize,
+    mut handle: DownloadHandle,
+    mut control: watch::Receiver<Control>,
+    tx: mpsc::Sender<(usize, Update)>,
+) {
+    loop {
+        tokio::select! {
+            update = handle.recv() => {
+                let Some(update) = update else {
+                    return;
+                };
+                let finished = update.is_finished();
+                let _ = tx.send((index, update)).await;
+                if finished {
+                    return;
+                }
+            }
+            changed = control.changed() => {
+                let state = if changed.is_ok() {
+                    *control.borrow_and_update()
+                } else {
+                    Control::Cancelled
+                };
+                match state {
+                    Control::Running => handle.resume().await,
+                    Control::Paused => handle.pause().await,
+                    Control::Cancelled => {
+                        handle.cancel().await;
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let key = DownloadKey::new("https://example.com/file.pbo".to_string(), None);
+        assert_eq!(split(&key, None, 4), None);
+        assert_eq!(split(&key, Some(7), 4), None);
+        assert_eq!(split(&key, Some(8), 4), Some(vec![(0, 3), (4, 7)]));
+        assert_eq!(split(&key, Some(10), 4), Some(vec![(0, 3), (4, 7), (8, 9)]));
+        assert_eq!(split(&key, Some(10), 0), None);
+        let key = DownloadKey::new("https://example.com/file.pbo".to_string(), Some((0, 9)));
+        assert_eq!(split(&key, Some(10), 4), None);
+    }
+}
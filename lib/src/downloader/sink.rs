@@ -0,0 +1,302 @@
+use std::{
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use ring::digest::{Context, SHA256};
+use serde::{Deserialize, Serialize};
+
+use super::partial::Partial;
+
+/// Where a download is written to as it streams in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Sink {
+    /// Keep the whole download in memory.
+    #[default]
+    Memory,
+    /// Stream the download to a file, creating parent folders as needed.
+    ///
+    /// The file is written as `<file>.part` until complete, and resumed if
+    /// the download is interrupted, even across restarts.
+    File(PathBuf),
+    /// Only hash the download with SHA-256, discarding the bytes.
+    Hash,
+    /// Write one segment of a download split across workers into the `<file>.part` of the
+    /// whole download, starting at `offset`.
+    ///
+    /// Each segment records its progress next to the file so it can be resumed. The file
+    /// is verified and moved into place once every segment is done.
+    Segment { path: PathBuf, offset: u64 },
+}
+
+impl Sink {
+    /// A partial download of `url` left behind by an earlier attempt, that can be resumed.
+    pub(crate) fn partial(&self, url: &str) -> Option<Partial> {
+        match self {
+            Self::File(path) => Partial::load(path, url),
+            Self::Segment { path, offset } => Partial::load_segment(path, *offset, url),
+            Self::Memory | Self::Hash => None,
+        }
+    }
+
+    /// Open a writer for a download, continuing a partial download after `offset` bytes.
+    pub(crate) fn open(
+        &self,
+        url: &str,
+        validator: Option<String>,
+        offset: u64,
+    ) -> std::io::Result<Box<dyn SinkWriter>> {
+        Ok(match self {
+            Self::Memory => Box::new(MemoryWriter(Vec::new())),
+            Self::File(path) => Box::new(FileWriter::open(path, url, validator, offset)?),
+            Self::Segment {
+                path,
+                offset: start,
+            } => Box::new(FileWriter::open_segment(
+                path, *start, url, validator, offset,
+            )?),
+            Self::Hash => Box::new(HashWriter(Context::new(&SHA256))),
+        })
+    }
+
+    /// Remove anything left behind by a download that will not continue.
+    ///
+    /// The file of a segment is shared with the other segments, only its progress is removed.
+    pub(crate) fn discard(&self) {
+        match self {
+            Self::File(path) => Partial::discard(path),
+            Self::Segment { path, offset } => {
+                let _ = std::fs::remove_file(Partial::segment_path(path, *offset));
+            }
+            Self::Memory | Self::Hash => {}
+        }
+    }
+}
+
+/// The result of a finished download, cheap to clone.
+#[derive(Debug, Clone)]
+pub enum Output {
+    /// The downloaded bytes.
+    Memory(Arc<[u8]>),
+    /// The file the download was written to.
+    File(PathBuf),
+    /// The SHA-256 hash of the download.
+    Hash(Vec<u8>),
+}
+
+/// Receives the chunks of a download.
+pub(crate) trait SinkWriter: Write + Send {
+    /// Persist progress so far, so the download can be resumed after a restart.
+    fn checkpoint(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Flush everything and produce the output.
+    fn finish(self: Box<Self>) -> std::io::Result<Output>;
+}
+
+struct MemoryWriter(Vec<u8>);
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SinkWriter for MemoryWriter {
+    fn finish(self: Box<Self>) -> std::io::Result<Output> {
+        Ok(Output::Memory(self.0.into()))
+    }
+}
+
+/// Writes to a partial file, which is moved into place once complete.
+struct FileWriter {
+    path: PathBuf,
+    /// Where the progress is recorded, the partial file is only moved into place if it
+    /// isn't a segment's.
+    sidecar: PathBuf,
+    segment: bool,
+    file: BufWriter<std::fs::File>,
+    partial: Partial,
+}
+
+impl FileWriter {
+    fn open(
+        path: &Path,
+        url: &str,
+        validator: Option<String>,
+        offset: u64,
+    ) -> std::io::Result<Self> {
+        let file = Self::create(path, offset == 0)?;
+        // Anything after the recorded length may not have been written completely
+        file.set_len(offset)?;
+        Self::new(
+            path,
+            Partial::sidecar_path(path),
+            false,
+            file,
+            offset,
+            Partial::new(url.to_string(), validator, offset),
+        )
+    }
+
+    /// Open the segment starting at `start`, continuing after `offset` bytes of it.
+    fn open_segment(
+        path: &Path,
+        start: u64,
+        url: &str,
+        validator: Option<String>,
+        offset: u64,
+    ) -> std::io::Result<Self> {
+        // The other segments are written to the same file
+        let file = Self::create(path, false)?;
+        Self::new(
+            path,
+            Partial::segment_path(path, start),
+            true,
+            file,
+            start + offset,
+            Partial::new(url.to_string(), validator, offset),
+        )
+    }
+
+    /// Open the partial file of `path`, creating it and its parent folders as needed.
+    fn create(path: &Path, truncate: bool) -> std::io::Result<std::fs::File> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(truncate)
+            .open(Partial::part_path(path))
+    }
+
+    fn new(
+        path: &Path,
+        sidecar: PathBuf,
+        segment: bool,
+        file: std::fs::File,
+        position: u64,
+        partial: Partial,
+    ) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(file);
+        file.seek(SeekFrom::Start(position))?;
+        partial.save(&sidecar)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            sidecar,
+            segment,
+            file,
+            partial,
+        })
+    }
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.partial.add_received(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl SinkWriter for FileWriter {
+    fn checkpoint(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        self.partial.save(&self.sidecar)
+    }
+
+    fn finish(mut self: Box<Self>) -> std::io::Result<Output> {
+        self.file.flush()?;
+        let Self {
+            path,
+            sidecar,
+            segment,
+            file,
+            partial,
+        } = *self;
+        drop(file);
+        if segment {
+            // Recorded as complete, for the whole download to find
+            partial.save(&sidecar)?;
+            return Ok(Output::File(Partial::part_path(&path)));
+        }
+        std::fs::rename(Partial::part_path(&path), &path)?;
+        let _ = std::fs::remove_file(sidecar);
+        Ok(Output::File(path))
+    }
+}
+
+struct HashWriter(Context);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SinkWriter for HashWriter {
+    fn finish(self: Box<Self>) -> std::io::Result<Output> {
+        Ok(Output::Hash(self.0.finish().as_ref().to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested/file");
+        for sink in [Sink::Memory, Sink::File(path.clone()), Sink::Hash] {
+            let mut writer = sink.open("", None, 0).unwrap();
+            writer.write_all(b"hello ").unwrap();
+            writer.write_all(b"world").unwrap();
+            match writer.finish().unwrap() {
+                Output::Memory(bytes) => assert_eq!(&*bytes, b"hello world"),
+                Output::File(out) => {
+                    assert_eq!(out, path);
+                    assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+                }
+                Output::Hash(hash) => {
+                    assert_eq!(hash, ring::digest::digest(&SHA256, b"hello world").as_ref())
+                }
+            }
+        }
+
+        // Segments are written in place, in any order
+        let path = dir.path().join("segmented");
+        for (offset, data) in [(6, b"world"), (0, b"hello")] {
+            let sink = Sink::Segment {
+                path: path.clone(),
+                offset,
+            };
+            let mut writer = sink.open("", Some("\"v1\"".to_string()), 0).unwrap();
+            writer.write_all(data).unwrap();
+            writer.finish().unwrap();
+            assert_eq!(sink.partial("").unwrap().received(), 5);
+        }
+        let part = Partial::part_path(&path);
+        assert_eq!(&std::fs::read(&part).unwrap()[6..], b"world");
+        assert_eq!(&std::fs::read(&part).unwrap()[..5], b"hello");
+        Sink::Segment { path, offset: 6 }.discard();
+        assert!(part.exists());
+    }
+}
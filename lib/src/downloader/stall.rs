@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+use super::{client::ClientConfig, error::Timeout};
+
+/// Notices a transfer that stopped sending data, or is too slow to be worth waiting for.
+pub(crate) struct Stall {
+    read_timeout: Duration,
+    /// Bytes per second the transfer must average over the duration.
+    low_speed: Option<(u64, Duration)>,
+    /// When data was last received.
+    last: Instant,
+    /// The start of the window the speed is measured over, and the bytes received in it.
+    window: (Instant, u64),
+}
+
+impl Stall {
+    pub fn new(config: &ClientConfig, now: Instant) -> Self {
+        Self {
+            read_timeout: config.read_timeout(),
+            low_speed: config.low_speed(),
+            last: now,
+            window: (now, 0),
+        }
+    }
+
+    /// When to check on the transfer if no data arrives.
+    pub fn deadline(&self) -> Instant {
+        let idle = self.last + self.read_timeout;
+        self.low_speed
+            .map_or(idle, |(_, duration)| idle.min(self.window.0 + duration))
+    }
+
+    /// Record `bytes` received.
+    pub fn received(&mut self, bytes: u64, now: Instant) -> Result<(), Timeout> {
+        self.last = now;
+        self.window.1 += bytes;
+        self.check(now)
+    }
+
+    /// The deadline has passed without any data.
+    pub fn expired(&mut self, now: Instant) -> Result<(), Timeout> {
+        if now >= self.last + self.read_timeout {
+            return Err(Timeout::Idle);
+        }
+        self.check(now)
+    }
+
+    /// Don't count time spent paused or waiting on the rate limiter against the transfer.
+    pub fn skip(&mut self, duration: Duration) {
+        self.last += duration;
+        self.window.0 += duration;
+    }
+
+    /// Check the speed over the window once it is complete, and start the next one.
+    fn check(&mut self, now: Instant) -> Result<(), Timeout> {
+        let Some((min, duration)) = self.low_speed else {
+            return Ok(());
+        };
+        let elapsed = now.saturating_duration_since(self.window.0);
+        if elapsed < duration {
+            return Ok(());
+        }
+        if (self.window.1 as f64) < min as f64 * elapsed.as_secs_f64() {
+            return Err(Timeout::LowSpeed);
+        }
+        self.window = (now, 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stall() {
+        let config = ClientConfig::default()
+            .with_read_timeout(Duration::from_secs(10))
+            .with_low_speed(Some((100, Duration::from_secs(5))));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let mut stall = Stall::new(&config, start);
+        assert_eq!(stall.deadline(), at(5));
+        assert_eq!(stall.received(400, at(4)), Ok(()));
+        // 600 bytes over 5 seconds is fast enough, the next window starts
+        assert_eq!(stall.received(200, at(5)), Ok(()));
+        assert_eq!(stall.deadline(), at(10));
+        assert_eq!(stall.received(100, at(10)), Err(Timeout::LowSpeed));
+
+        let mut stall = Stall::new(&config.clone().with_low_speed(None), start);
+        assert_eq!(stall.deadline(), at(10));
+        assert_eq!(stall.expired(at(9)), Ok(()));
+        assert_eq!(stall.expired(at(10)), Err(Timeout::Idle));
+
+        // Time spent paused doesn't count
+        let mut stall = Stall::new(&config, start);
+        stall.skip(Duration::from_secs(60));
+        assert_eq!(stall.expired(at(61)), Ok(()));
+        assert_eq!(stall.expired(at(65)), Err(Timeout::LowSpeed));
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use super::{
+    error::DownloadError,
+    events::Event,
+    pool::{DownloadKey, Inner},
+    queue::DownloadOptions,
+    worker::Update,
+};
+
+/// How often statistics are sent.
+const INTERVAL: Duration = Duration::from_secs(1);
+/// Weight of the newest second in the smoothed speed.
+const ALPHA: f64 = 0.2;
+
+/// Progress of all downloads of the pool or of a job, since it was last idle.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    /// Bytes of every download, as far as their size is known.
+    queued: u64,
+    done: u64,
+    /// Smoothed bytes per second.
+    speed: f64,
+    active: u32,
+    completed: u32,
+    failed: u32,
+    /// Downloads that needed more than one attempt.
+    retried: u32,
+}
+
+impl Stats {
+    pub fn queued(&self) -> u64 {
+        self.queued
+    }
+
+    pub fn done(&self) -> u64 {
+        self.done
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.queued.saturating_sub(self.done)
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// How long the remaining bytes take at the current speed.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.remaining() == 0 {
+            return Some(Duration::ZERO);
+        }
+        (self.speed >= 1.0).then(|| Duration::from_secs_f64(self.remaining() as f64 / self.speed))
+    }
+
+    /// Downloads pending or in progress.
+    pub fn active(&self) -> u32 {
+        self.active
+    }
+
+    pub fn completed(&self) -> u32 {
+        self.completed
+    }
+
+    pub fn failed(&self) -> u32 {
+        self.failed
+    }
+
+    pub fn retried(&self) -> u32 {
+        self.retried
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.active == 0
+    }
+}
+
+/// Tracks downloads requested from the pool, segments are counted as part of their download.
+#[derive(Default)]
+pub(crate) struct Tracker {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    downloads: HashMap<DownloadKey, Download>,
+    pool: Group,
+    jobs: HashMap<String, Group>,
+    next_seq: u64,
+}
+
+struct Download {
+    options: DownloadOptions,
+    /// Order the download was requested in.
+    seq: u64,
+    total: Option<u64>,
+    downloaded: u64,
+    retried: bool,
+}
+
+/// Totals of a group's finished downloads, its active downloads are added on top.
+#[derive(Default)]
+struct Group {
+    stats: Stats,
+    /// Done bytes at the last sample.
+    sampled: u64,
+}
+
+impl State {
+    fn groups(&mut self, job: Option<&str>) -> impl Iterator<Item = &mut Group> {
+        let job = match job {
+            Some(job) => Some(self.jobs.entry(job.to_string()).or_default()),
+            None => None,
+        };
+        std::iter::once(&mut self.pool).chain(job)
+    }
+
+    /// The stats of a group with its active downloads.
+    fn stats(&self, group: &Group, job: Option<&str>) -> Stats {
+        let mut stats = group.stats;
+        for download in self.downloads.values() {
+            if job.is_some() && download.options.job() != job {
+                continue;
+            }
+            stats.queued += download.total.unwrap_or(download.downloaded);
+            stats.done += download.downloaded;
+            stats.active += 1;
+        }
+        stats
+    }
+}
+
+impl Tracker {
+    /// Start counting a download requested from the pool, returning `false` if it is already
+    /// counted.
+    pub fn track(&self, key: &DownloadKey, options: &DownloadOptions) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.downloads.contains_key(key) {
+            return false;
+        }
+        let total = key
+            .range()
+            .map(|(start, end)| end - start + 1)
+            .or(key.size())
+            .or(options.size());
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.downloads.insert(
+            key.clone(),
+            Download {
+                options: options.clone(),
+                seq,
+                total,
+                downloaded: 0,
+                retried: false,
+            },
+        );
+        if let Some(job) = options.job() {
+            state.jobs.entry(job.to_string()).or_default();
+        }
+        true
+    }
+
+    /// Apply an update that isn't final, returning the job of the download if it is tracked.
+    pub fn update(&self, update: &Update) -> Option<Option<String>> {
+        let mut state = self.state.lock().unwrap();
+        let download = state.downloads.get_mut(update.key())?;
+        let job = download.options.job().map(String::from);
+        match update {
+            Update::Progress {
+                downloaded, total, ..
+            } => {
+                download.downloaded = *downloaded;
+                if *total != 0 {
+                    download.total = Some(*total);
+                }
+            }
+            Update::Retrying { .. } if !download.retried => {
+                download.retried = true;
+                for group in state.groups(job.as_deref()) {
+                    group.stats.retried += 1;
+                }
+            }
+            _ => {}
+        }
+        Some(job)
+    }
+
+    /// Count a finished download and stop tracking it, returning its job if it was tracked.
+    pub fn finish(&self, update: &Update) -> Option<Option<String>> {
+        let mut state = self.state.lock().unwrap();
+        let download = state.downloads.remove(update.key())?;
+        for group in state.groups(download.options.job()) {
+            match update {
+                Update::Done(..) => {
+                    let size = download.total.unwrap_or(download.downloaded);
+                    group.stats.queued += size;
+                    group.stats.done += size;
+                    group.stats.completed += 1;
+                }
+                // The rest of the download won't arrive
+                Update::Failed(_, _, error) => {
+                    group.stats.queued += download.downloaded;
+                    group.stats.done += download.downloaded;
+                    if *error != DownloadError::Cancelled {
+                        group.stats.failed += 1;
+                    }
+                }
+                Update::Started { .. } | Update::Progress { .. } | Update::Retrying { .. } => {}
+            }
+        }
+        Some(download.options.job().map(String::from))
+    }
+
+    /// Stop tracking a download that was dropped before it started, returning its job if it
+    /// was tracked.
+    pub fn forget(&self, key: &DownloadKey) -> Option<Option<String>> {
+        self.state
+            .lock()
+            .unwrap()
+            .downloads
+            .remove(key)
+            .map(|download| download.options.job().map(String::from))
+    }
+
+    /// Every download being tracked, in the order they were requested.
+    pub fn downloads(&self) -> Vec<(DownloadKey, DownloadOptions)> {
+        let state = self.state.lock().unwrap();
+        let mut downloads = state.downloads.iter().collect::<Vec<_>>();
+        downloads.sort_by_key(|(_, download)| download.seq);
+        downloads
+            .into_iter()
+            .map(|(key, download)| (key.clone(), download.options.clone()))
+            .collect()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let state = self.state.lock().unwrap();
+        state.stats(&state.pool, None)
+    }
+
+    pub fn job(&self, job: &str) -> Option<Stats> {
+        let state = self.state.lock().unwrap();
+        state
+            .jobs
+            .get(job)
+            .map(|group| state.stats(group, Some(job)))
+    }
+
+    /// Smooth the speed of every group over the last `elapsed`, returning the stats of the
+    /// pool and of each job.
+    ///
+    /// Groups that are idle are reset, after their final stats are returned.
+    fn sample(&self, elapsed: Duration) -> Vec<(Option<String>, Stats)> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let mut sampled = Vec::new();
+        let jobs = state.jobs.keys().cloned().map(Some);
+        for job in std::iter::once(None).chain(jobs.collect::<Vec<_>>()) {
+            let group = match &job {
+                Some(job) => &state.jobs[job],
+                None => &state.pool,
+            };
+            let mut stats = state.stats(group, job.as_deref());
+            let speed = stats.done.saturating_sub(group.sampled) as f64 / elapsed.as_secs_f64();
+            stats.speed = if stats.is_idle() {
+                0.0
+            } else {
+                group.stats.speed * (1.0 - ALPHA) + speed * ALPHA
+            };
+            let group = match &job {
+                Some(job) => state.jobs.get_mut(job).unwrap(),
+                None => &mut state.pool,
+            };
+            if stats.is_idle() {
+                *group = Group::default();
+            } else {
+                group.stats.speed = stats.speed;
+                group.sampled = stats.done;
+            }
+            sampled.push((job, stats));
+        }
+        state.jobs.retain(|job, _| {
+            !sampled
+                .iter()
+                .any(|(j, stats)| j.as_deref() == Some(job.as_str()) && stats.is_idle())
+        });
+        sampled
+    }
+}
+
+/// Broadcast the statistics of busy groups every second, and once more when they become idle.
+pub(crate) async fn run(inner: Weak<Inner>) {
+    let mut last = Instant::now();
+    let mut busy = Vec::new();
+    loop {
+        tokio::time::sleep(INTERVAL).await;
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let sampled = inner.tracker().sample(last.elapsed());
+        last = Instant::now();
+        let was_busy = std::mem::take(&mut busy);
+        for (job, stats) in sampled {
+            if stats.is_idle() && !was_busy.contains(&job) {
+                continue;
+            }
+            if !stats.is_idle() {
+                busy.push(job.clone());
+            }
+            inner.send_event(Event::Stats(job, stats));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(key: &DownloadKey, downloaded: u64, total: u64) -> Update {
+        Update::Progress {
+            id: 0,
+            key: key.clone(),
+            downloaded,
+            total,
+            speed: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let tracker = Tracker::default();
+        let a = DownloadKey::new("https://example.com/a.pbo".to_string(), None).with_size(100);
+        let b = DownloadKey::new("https://example.com/b.pbo".to_string(), None);
+        let c = DownloadKey::new("https://example.com/c.pbo".to_string(), None);
+        assert!(tracker.track(&a, &DownloadOptions::default().with_job("ace")));
+        assert!(tracker.track(&b, &DownloadOptions::default().with_job("ace")));
+        assert!(tracker.track(&c, &DownloadOptions::default()));
+        assert!(!tracker.track(&a, &DownloadOptions::default()));
+
+        let stats = tracker.job("ace").unwrap();
+        assert_eq!((stats.queued(), stats.active()), (100, 2));
+
+        tracker.update(&progress(&a, 50, 100));
+        tracker.update(&progress(&b, 10, 200));
+        let retrying = Update::Retrying {
+            id: 0,
+            key: b.clone(),
+            attempt: 1,
+            delay: Duration::ZERO,
+            error: DownloadError::Status(503),
+        };
+        tracker.update(&retrying);
+        tracker.update(&retrying);
+        let stats = tracker.job("ace").unwrap();
+        assert_eq!(
+            (stats.queued(), stats.done(), stats.remaining()),
+            (300, 60, 240)
+        );
+        assert_eq!(stats.retried(), 1);
+
+        let sampled = tracker.sample(Duration::from_secs(1));
+        let (_, stats) = sampled
+            .iter()
+            .find(|(job, _)| job.as_deref() == Some("ace"))
+            .unwrap();
+        assert!((stats.speed() - 12.0).abs() < f64::EPSILON);
+        assert_eq!(stats.eta(), Some(Duration::from_secs(20)));
+
+        tracker.finish(&Update::Failed(0, b.clone(), DownloadError::Status(404)));
+        tracker.finish(&Update::Done(
+            0,
+            a.clone(),
+            crate::downloader::Output::Hash(Vec::new()),
+        ));
+        let stats = tracker.job("ace").unwrap();
+        assert_eq!(
+            (stats.queued(), stats.done(), stats.remaining()),
+            (110, 110, 0)
+        );
+        assert_eq!(
+            (stats.completed(), stats.failed(), stats.active()),
+            (1, 1, 0)
+        );
+        assert_eq!(tracker.stats().active(), 1);
+
+        // An idle job is reset once its final stats are sampled
+        let sampled = tracker.sample(Duration::from_secs(1));
+        assert!(sampled
+            .iter()
+            .any(|(job, stats)| job.as_deref() == Some("ace") && stats.is_idle()));
+        assert_eq!(tracker.job("ace"), None);
+
+        assert_eq!(tracker.forget(&c), Some(None));
+        assert!(tracker.stats().is_idle());
+    }
+}
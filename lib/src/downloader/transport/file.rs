@@ -0,0 +1,162 @@
+use std::{io::SeekFrom, path::PathBuf, time::UNIX_EPOCH};
+
+use bytes::Bytes;
+use reqwest::Url;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::{Body, BoxFuture, Request, Response, Transport, TransportError};
+use crate::downloader::error::DownloadError;
+
+/// Size of the chunks read from a file.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Copies `file://` URLs from the local filesystem, such as a repository on a network share.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileTransport;
+
+impl FileTransport {
+    async fn read(&self, request: Request<'_>) -> Result<Response, TransportError> {
+        let path = path(request.url())?;
+        let mut file = tokio::fs::File::open(&path)
+            .await
+            .map_err(|e| DownloadError::Io(format!("{}: {e}", path.display())))?;
+        let metadata = file
+            .metadata()
+            .await
+            .map_err(|e| DownloadError::Io(e.to_string()))?;
+        // The size and modification time identify a version of the file
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |m| m.as_nanos());
+        let validator = format!("{}-{modified}", metadata.len());
+        let resumed = request.offset() != 0 && request.validator() == Some(validator.as_str());
+
+        let start = if resumed {
+            request.start()
+        } else {
+            request.range().map_or(0, |(start, _)| start)
+        };
+        let end = request
+            .range()
+            .map_or(metadata.len(), |(_, end)| end.saturating_add(1))
+            .min(metadata.len());
+        if start > end {
+            return Err(DownloadError::Io(format!(
+                "{}: range starts after the end of the file",
+                path.display()
+            ))
+            .into());
+        }
+        file.seek(SeekFrom::Start(start))
+            .await
+            .map_err(|e| DownloadError::Io(e.to_string()))?;
+        Ok(Response::new(
+            Box::new(FileBody {
+                file,
+                remaining: end - start,
+            }),
+            resumed,
+            Some(end - start),
+            Some(validator),
+        ))
+    }
+}
+
+impl Transport for FileTransport {
+    fn open<'a>(&'a self, request: Request<'a>) -> BoxFuture<'a, Result<Response, TransportError>> {
+        Box::pin(self.read(request))
+    }
+}
+
+struct FileBody {
+    file: tokio::fs::File,
+    /// Bytes left until the end of the range.
+    remaining: u64,
+}
+
+impl Body for FileBody {
+    fn chunk(&mut self) -> BoxFuture<'_, Result<Option<Bytes>, DownloadError>> {
+        Box::pin(async move {
+            let len = self.remaining.min(CHUNK_SIZE as u64) as usize;
+            if len == 0 {
+                return Ok(None);
+            }
+            let mut buf = vec![0; len];
+            let read = self
+                .file
+                .read(&mut buf)
+                .await
+                .map_err(|e| DownloadError::Io(e.to_string()))?;
+            if read == 0 {
+                return Ok(None);
+            }
+            buf.truncate(read);
+            self.remaining -= read as u64;
+            Ok(Some(Bytes::from(buf)))
+        })
+    }
+}
+
+fn path(url: &str) -> Result<PathBuf, DownloadError> {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .ok_or_else(|| DownloadError::Io(format!("Not a file URL: {url}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read the whole body, returning the validator and the data.
+    async fn read(transport: &FileTransport, request: Request<'_>) -> (Option<String>, Vec<u8>) {
+        let response = transport.open(request).await.unwrap();
+        let (mut body, validator) = response.into_parts();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.chunk().await.unwrap() {
+            data.extend_from_slice(&chunk);
+        }
+        (validator, data)
+    }
+
+    #[tokio::test]
+    async fn test_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mod.pbo");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let url = Url::from_file_path(&path).unwrap().to_string();
+        let transport = FileTransport;
+
+        let (validator, data) = read(&transport, Request::new(&url, None, 0, None)).await;
+        assert_eq!(data, b"0123456789");
+        let validator = validator.unwrap();
+
+        let (_, data) = read(&transport, Request::new(&url, Some((2, 5)), 0, None)).await;
+        assert_eq!(data, b"2345");
+        let (_, data) = read(&transport, Request::new(&url, Some((2, 5)), 1, None)).await;
+        assert_eq!(data, b"2345");
+
+        let response = transport
+            .open(Request::new(&url, Some((2, 5)), 1, Some(&validator)))
+            .await
+            .unwrap();
+        assert!(response.resumed());
+        assert_eq!(response.length(), Some(3));
+
+        // A different version of the file starts over
+        let response = transport
+            .open(Request::new(&url, None, 4, Some("other")))
+            .await
+            .unwrap();
+        assert!(!response.resumed());
+        assert_eq!(response.length(), Some(10));
+
+        let missing = Url::from_file_path(dir.path().join("missing.pbo")).unwrap();
+        assert!(transport
+            .open(Request::new(missing.as_str(), None, 0, None))
+            .await
+            .is_err());
+    }
+}
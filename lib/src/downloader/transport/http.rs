@@ -0,0 +1,372 @@
+use std::{collections::HashMap, error::Error, io, sync::Mutex};
+
+use bytes::Bytes;
+use reqwest::{
+    header::{ETAG, IF_RANGE, LAST_MODIFIED, RANGE},
+    Client, StatusCode, Url,
+};
+use rustls::CertificateError;
+use tokio::sync::watch;
+
+use super::{scheme, Body, BoxFuture, Request, Response, Transport, TransportError};
+use crate::downloader::{
+    client::ClientConfig,
+    error::{DownloadError, Timeout},
+    host::{self, HostConfig, Hosts},
+    retry::retry_after,
+};
+
+/// The pin of a host and whether it is private, what its client is built for.
+type ClientKey = (Option<String>, bool);
+
+/// Downloads over HTTP and HTTPS, with the pool's shared client and host settings.
+pub(crate) struct HttpTransport {
+    client: watch::Receiver<Client>,
+    config: watch::Receiver<ClientConfig>,
+    hosts: watch::Receiver<Hosts>,
+    /// Clients for pinned or private hosts, and the config they were built with.
+    clients: Mutex<(ClientConfig, HashMap<ClientKey, Client>)>,
+}
+
+impl HttpTransport {
+    pub fn new(
+        client: watch::Receiver<Client>,
+        config: watch::Receiver<ClientConfig>,
+        hosts: watch::Receiver<Hosts>,
+    ) -> Self {
+        let clients = Mutex::new((config.borrow().clone(), HashMap::new()));
+        Self {
+            client,
+            config,
+            hosts,
+            clients,
+        }
+    }
+
+    /// The client to use for `host`, one that only connects to it if it has a pin and
+    /// doesn't follow redirects elsewhere if it is private.
+    fn client(&self, host: &HostConfig) -> Result<Client, DownloadError> {
+        let (pin, private) = (host.pin(), host.is_private());
+        if pin.is_none() && !private {
+            return Ok(self.client.borrow().clone());
+        }
+        let config = self.config.borrow();
+        let mut clients = self.clients.lock().unwrap();
+        if clients.0 != *config {
+            *clients = (config.clone(), HashMap::new());
+        }
+        let key = (pin.map(str::to_string), private);
+        if let Some(client) = clients.1.get(&key) {
+            return Ok(client.clone());
+        }
+        let client = config
+            .build_for(pin, private)
+            .map_err(|e| DownloadError::Connection(e.to_string()))?;
+        clients.1.insert(key, client.clone());
+        Ok(client)
+    }
+
+    async fn get(&self, request: Request<'_>) -> Result<Response, TransportError> {
+        let host = host::lookup(&self.hosts.borrow(), request.url())
+            .cloned()
+            .unwrap_or_default();
+        // Without TLS there is no certificate to check, don't send the credentials in the clear
+        if host.pin().is_some() && scheme(request.url()) != "https" {
+            return Err(DownloadError::CertificateMismatch(host_name(request.url())).into());
+        }
+        let client = self.client(&host)?;
+        let mut resume = request.offset() != 0;
+        let response = loop {
+            let offset = if resume { request.offset() } else { 0 };
+            let mut req = host.get(&client, request.url());
+            match request.range() {
+                Some((start, end)) => {
+                    req = req.header(RANGE, format!("bytes={}-{}", start + offset, end));
+                }
+                None if offset != 0 => req = req.header(RANGE, format!("bytes={offset}-")),
+                None => {}
+            }
+            if let (true, Some(validator)) = (resume, request.validator()) {
+                req = req.header(IF_RANGE, validator);
+            }
+            let response = req.send().await.map_err(|e| {
+                if e.is_connect() && e.is_timeout() {
+                    DownloadError::Timeout(Timeout::Connect)
+                } else if pin_rejected(&e) {
+                    DownloadError::CertificateMismatch(host_name(request.url()))
+                } else {
+                    // The URL may hold a signed query string
+                    DownloadError::Connection(e.without_url().to_string())
+                }
+            })?;
+            if resume {
+                let changed = match response.status() {
+                    StatusCode::RANGE_NOT_SATISFIABLE => true,
+                    StatusCode::PARTIAL_CONTENT => validator(&response)
+                        .is_some_and(|v| Some(v.as_str()) != request.validator()),
+                    _ => false,
+                };
+                if changed {
+                    // The file changed on the server, start over
+                    resume = false;
+                    continue;
+                }
+            }
+            break response;
+        };
+        if !response.status().is_success() {
+            return Err(TransportError::new(
+                DownloadError::Status(response.status().as_u16()),
+                retry_after(&response),
+            ));
+        }
+
+        // A full response means the server ignored the range, or the file changed
+        let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+        // A server ignoring the range sends the whole file, skip to the start of the range.
+        let skip = match (partial, request.range()) {
+            (false, Some((start, _))) => start,
+            _ => 0,
+        };
+        let length = response.content_length().map(|l| l.saturating_sub(skip));
+        let validator = validator(&response);
+        Ok(Response::new(
+            Box::new(HttpBody { response, skip }),
+            resume && partial,
+            length,
+            validator,
+        ))
+    }
+}
+
+impl Transport for HttpTransport {
+    fn open<'a>(&'a self, request: Request<'a>) -> BoxFuture<'a, Result<Response, TransportError>> {
+        Box::pin(self.get(request))
+    }
+}
+
+struct HttpBody {
+    response: reqwest::Response,
+    /// Bytes to drop from the start of the body.
+    skip: u64,
+}
+
+impl Body for HttpBody {
+    fn chunk(&mut self) -> BoxFuture<'_, Result<Option<Bytes>, DownloadError>> {
+        Box::pin(async move {
+            loop {
+                let Some(mut chunk) = self
+                    .response
+                    .chunk()
+                    .await
+                    .map_err(|e| DownloadError::Connection(e.without_url().to_string()))?
+                else {
+                    return Ok(None);
+                };
+                if self.skip > 0 {
+                    let skipped = self.skip.min(chunk.len() as u64);
+                    self.skip -= skipped;
+                    chunk = chunk.slice(skipped as usize..);
+                }
+                if !chunk.is_empty() {
+                    return Ok(Some(chunk));
+                }
+            }
+        })
+    }
+}
+
+/// Was the connection closed for the server not presenting the pinned certificate.
+fn pin_rejected(error: &reqwest::Error) -> bool {
+    let mut source: Option<&(dyn Error + 'static)> = Some(error);
+    while let Some(mut error) = source {
+        // The source of an IO error skips the error it wraps
+        while let Some(inner) = error
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::get_ref)
+        {
+            error = inner;
+        }
+        if let Some(rustls::Error::InvalidCertificate(
+            CertificateError::ApplicationVerificationFailure,
+        )) = error.downcast_ref()
+        {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+fn host_name(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+        .unwrap_or_default()
+}
+
+/// The value to send as `If-Range` when resuming a download of the response.
+fn validator(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(ETAG)
+        .or_else(|| response.headers().get(LAST_MODIFIED))
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::Arc,
+        thread::{self, JoinHandle},
+    };
+
+    use rcgen::CertifiedKey;
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    use super::*;
+    use crate::{
+        downloader::host::{fingerprint, Credentials},
+        repo::Password,
+        testing::MockServer,
+    };
+
+    /// Accept one TLS connection presenting `cert`, returning the request received.
+    fn serve(cert: &CertifiedKey) -> (u16, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.cert.der().clone()], PrivateKeyDer::Pkcs8(key))
+        .unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let connection = rustls::ServerConnection::new(Arc::new(config)).unwrap();
+            let mut stream = rustls::StreamOwned::new(connection, stream);
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => return request,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok");
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+            request
+        });
+        (port, server)
+    }
+
+    #[tokio::test]
+    async fn test_redirect() {
+        let a = MockServer::start([("a.pbo".to_string(), b"a".to_vec())])
+            .await
+            .unwrap();
+        let b = MockServer::start([("a.pbo".to_string(), b"b".to_vec())])
+            .await
+            .unwrap();
+        a.redirect("old.pbo", a.url("a.pbo"));
+        a.redirect("moved.pbo", b.url("a.pbo"));
+        let transport = |host: HostConfig| {
+            HttpTransport::new(
+                watch::channel(ClientConfig::default().build().unwrap()).1,
+                watch::channel(ClientConfig::default()).1,
+                watch::channel(Hosts::from([(a.addr().to_string(), host)])).1,
+            )
+        };
+        let open = |host: HostConfig, path: &str| {
+            let url = a.url(path);
+            async move {
+                transport(host)
+                    .open(Request::new(&url, None, 0, None))
+                    .await
+            }
+        };
+
+        // reqwest strips the `Authorization` header itself
+        let bearer = Credentials::Bearer(Password::new("secret".to_string()));
+        let host = HostConfig::default().with_credentials(bearer);
+        assert!(open(host, "moved.pbo").await.is_ok());
+        assert_eq!(b.requests("a.pbo"), 1);
+
+        // Other secrets stay with their host, even if it redirects elsewhere
+        let header = HostConfig::default()
+            .with_header("x-token", Password::new("secret".to_string()))
+            .unwrap();
+        let query = HostConfig::default()
+            .with_credentials(Credentials::Query(Password::new("sig=secret".to_string())));
+        for host in [header, query] {
+            assert!(open(host.clone(), "old.pbo").await.is_ok());
+            let Err(error) = open(host, "moved.pbo").await else {
+                panic!("Followed a redirect to another host");
+            };
+            assert_eq!(error.error(), &DownloadError::Status(302));
+        }
+        assert_eq!(b.requests("a.pbo"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pin() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let transport = |port: u16, pin: &str| {
+            let host = HostConfig::default()
+                .with_credentials(Credentials::Bearer(Password::new("secret".to_string())))
+                .with_pin(pin);
+            let hosts = Hosts::from([(format!("127.0.0.1:{port}"), host)]);
+            HttpTransport::new(
+                watch::channel(ClientConfig::default().build().unwrap()).1,
+                watch::channel(ClientConfig::default()).1,
+                watch::channel(hosts).1,
+            )
+        };
+
+        // The pinned certificate is trusted, even self-signed
+        let (port, server) = serve(&cert);
+        let url = format!("https://127.0.0.1:{port}/a.pbo");
+        let result = transport(port, &fingerprint(cert.cert.der()))
+            .open(Request::new(&url, None, 0, None))
+            .await;
+        assert!(result.is_ok());
+        let request = String::from_utf8(server.join().unwrap()).unwrap();
+        assert!(request.contains("Bearer secret"));
+
+        // Another certificate is refused before anything is sent
+        let (port, server) = serve(&cert);
+        let url = format!("https://127.0.0.1:{port}/a.pbo");
+        let Err(error) = transport(port, &"00".repeat(32))
+            .open(Request::new(&url, None, 0, None))
+            .await
+        else {
+            panic!("Connected with the wrong certificate");
+        };
+        assert_eq!(
+            error.error(),
+            &DownloadError::CertificateMismatch("127.0.0.1".to_string())
+        );
+        assert!(server.join().unwrap().is_empty());
+
+        // As is plain HTTP
+        let url = format!("http://127.0.0.1:{port}/a.pbo");
+        let Err(error) = transport(port, &"00".repeat(32))
+            .open(Request::new(&url, None, 0, None))
+            .await
+        else {
+            panic!("Sent the credentials in the clear");
+        };
+        assert!(matches!(
+            error.error(),
+            DownloadError::CertificateMismatch(_)
+        ));
+    }
+}
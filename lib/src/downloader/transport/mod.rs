@@ -0,0 +1,185 @@
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use reqwest::Client;
+use tokio::sync::watch;
+
+use super::{client::ClientConfig, error::DownloadError, host::Hosts};
+
+mod file;
+mod http;
+
+pub use file::FileTransport;
+use http::HttpTransport;
+
+/// A boxed future, so transports can be used as trait objects.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Transports by URL scheme.
+pub(crate) type Transports = HashMap<String, Arc<dyn Transport>>;
+
+/// Fetches files for a URL scheme, such as `https` or `file`.
+pub trait Transport: Send + Sync {
+    /// Open `request` for reading.
+    fn open<'a>(&'a self, request: Request<'a>) -> BoxFuture<'a, Result<Response, TransportError>>;
+}
+
+/// The chunks of an opened file.
+pub trait Body: Send {
+    /// The next chunk, `None` at the end.
+    fn chunk(&mut self) -> BoxFuture<'_, Result<Option<Bytes>, DownloadError>>;
+}
+
+/// A request for a file, or a range of it.
+#[derive(Debug, Clone, Copy)]
+pub struct Request<'a> {
+    url: &'a str,
+    range: Option<(u64, u64)>,
+    offset: u64,
+    validator: Option<&'a str>,
+}
+
+impl<'a> Request<'a> {
+    pub(crate) fn new(
+        url: &'a str,
+        range: Option<(u64, u64)>,
+        offset: u64,
+        validator: Option<&'a str>,
+    ) -> Self {
+        Self {
+            url,
+            range,
+            offset,
+            validator,
+        }
+    }
+
+    pub fn url(&self) -> &'a str {
+        self.url
+    }
+
+    /// The inclusive range of the file requested, `None` for the whole file.
+    pub fn range(&self) -> Option<(u64, u64)> {
+        self.range
+    }
+
+    /// Bytes of the range already received by an earlier attempt.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Identifies the version of the file the earlier attempt received.
+    ///
+    /// The offset should only be skipped if the file still matches.
+    pub fn validator(&self) -> Option<&'a str> {
+        self.validator
+    }
+
+    /// The first byte of the file to read, including the offset.
+    pub fn start(&self) -> u64 {
+        self.range.map_or(0, |(start, _)| start) + self.offset
+    }
+}
+
+/// An opened file.
+pub struct Response {
+    body: Box<dyn Body>,
+    resumed: bool,
+    length: Option<u64>,
+    validator: Option<String>,
+}
+
+impl Response {
+    /// A response whose body starts at the start of the requested range, or after
+    /// the offset if `resumed`.
+    ///
+    /// The body may continue past the end of the range, the rest is ignored.
+    pub fn new(
+        body: Box<dyn Body>,
+        resumed: bool,
+        length: Option<u64>,
+        validator: Option<String>,
+    ) -> Self {
+        Self {
+            body,
+            resumed,
+            length,
+            validator,
+        }
+    }
+
+    /// Does the body continue after the offset of the request.
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// The length of the body, if known.
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    /// Identifies this version of the file, for resuming it later.
+    pub fn validator(&self) -> Option<&str> {
+        self.validator.as_deref()
+    }
+
+    pub(crate) fn into_parts(self) -> (Box<dyn Body>, Option<String>) {
+        (self.body, self.validator)
+    }
+}
+
+/// A failed request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportError {
+    error: DownloadError,
+    retry_after: Option<Duration>,
+}
+
+impl TransportError {
+    /// Fail, asking to wait for `retry_after` before retrying.
+    pub fn new(error: DownloadError, retry_after: Option<Duration>) -> Self {
+        Self { error, retry_after }
+    }
+
+    pub fn error(&self) -> &DownloadError {
+        &self.error
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after
+    }
+
+    pub(crate) fn into_parts(self) -> (DownloadError, Option<Duration>) {
+        (self.error, self.retry_after)
+    }
+}
+
+impl From<DownloadError> for TransportError {
+    fn from(error: DownloadError) -> Self {
+        Self::new(error, None)
+    }
+}
+
+/// The transports a pool starts with, for `http`, `https` and `file` URLs.
+pub(crate) fn defaults(
+    client: watch::Receiver<Client>,
+    config: watch::Receiver<ClientConfig>,
+    hosts: watch::Receiver<Hosts>,
+) -> Transports {
+    let http: Arc<dyn Transport> = Arc::new(HttpTransport::new(client, config, hosts));
+    Transports::from([
+        ("http".to_string(), http.clone()),
+        ("https".to_string(), http),
+        (
+            "file".to_string(),
+            Arc::new(FileTransport) as Arc<dyn Transport>,
+        ),
+    ])
+}
+
+/// The scheme of `url`, lowercase.
+pub(crate) fn scheme(url: &str) -> String {
+    url.split_once(':')
+        .map(|(scheme, _)| scheme.to_ascii_lowercase())
+        .unwrap_or_default()
+}
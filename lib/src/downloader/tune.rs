@@ -0,0 +1,267 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+
+use super::pool::Inner;
+
+/// How long each number of workers is measured for by default.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+/// Adding a worker must raise throughput by this factor to be kept.
+const IMPROVEMENT: f64 = 1.05;
+/// Latency this many times the usual is a spike.
+const SPIKE: u32 = 3;
+/// Intervals to wait after backing off before trying more workers again.
+const HOLD: u8 = 3;
+
+/// Limits for tuning the number of concurrent downloads to the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AutoTune {
+    min: u8,
+    max: u8,
+    interval: Duration,
+}
+
+impl AutoTune {
+    /// Tune between `min` and `max` concurrent downloads, at least one.
+    pub fn new(min: u8, max: u8) -> Self {
+        let min = min.max(1);
+        Self {
+            min,
+            max: max.max(min),
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+
+    /// Measure each number of workers for `interval` before changing it again.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn min(&self) -> u8 {
+        self.min
+    }
+
+    pub fn max(&self) -> u8 {
+        self.max
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+/// Measurements of all workers since the last sample.
+#[derive(Default)]
+pub(crate) struct Meter {
+    bytes: AtomicU64,
+    errors: AtomicU32,
+    /// Total time until responses started, and the number of responses.
+    latency: Mutex<(Duration, u32)>,
+}
+
+impl Meter {
+    pub fn received(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn latency(&self, latency: Duration) {
+        let mut total = self.latency.lock().unwrap();
+        total.0 += latency;
+        total.1 += 1;
+    }
+
+    /// Take the measurements over the last `elapsed`, starting the next sample.
+    fn sample(&self, elapsed: Duration, saturated: bool) -> Sample {
+        let (latency, responses) = std::mem::take(&mut *self.latency.lock().unwrap());
+        Sample {
+            throughput: self.bytes.swap(0, Ordering::Relaxed) as f64
+                / elapsed.as_secs_f64().max(f64::EPSILON),
+            errors: self.errors.swap(0, Ordering::Relaxed),
+            latency: (responses != 0).then(|| latency / responses),
+            saturated,
+        }
+    }
+}
+
+struct Sample {
+    /// Bytes per second of all workers.
+    throughput: f64,
+    errors: u32,
+    /// Average time until responses started.
+    latency: Option<Duration>,
+    /// Was every worker busy, otherwise more workers wouldn't have helped.
+    saturated: bool,
+}
+
+/// Climbs towards the number of workers with the highest throughput.
+#[derive(Default)]
+struct Tuner {
+    /// Throughput of the previous interval.
+    previous: Option<f64>,
+    /// Was a worker added for this interval.
+    rising: bool,
+    /// Intervals left before adding workers again.
+    hold: u8,
+    /// Usual latency, excluding spikes.
+    baseline: Option<Duration>,
+}
+
+impl Tuner {
+    /// The number of workers for the next interval, after `current` workers produced `sample`.
+    fn tick(&mut self, tune: &AutoTune, current: u8, sample: &Sample) -> u8 {
+        let current = current.clamp(tune.min, tune.max);
+        let spike = match (sample.latency, self.baseline) {
+            (Some(latency), Some(baseline)) => latency > baseline * SPIKE,
+            _ => false,
+        };
+        if let (Some(latency), false) = (sample.latency, spike) {
+            self.baseline = Some(
+                self.baseline
+                    .map_or(latency, |baseline| (baseline * 3 + latency) / 4),
+            );
+        }
+        if sample.errors > 0 || spike {
+            // The server or the connection is struggling, back off quickly
+            self.previous = None;
+            self.rising = false;
+            self.hold = HOLD;
+            return current.saturating_sub((current / 4).max(1)).max(tune.min);
+        }
+        if !sample.saturated {
+            // Not enough downloads to tell if more workers would help
+            self.previous = None;
+            self.rising = false;
+            return current;
+        }
+        if self.hold > 0 {
+            self.hold -= 1;
+            self.previous = Some(sample.throughput);
+            return current;
+        }
+        let next = match self.previous {
+            Some(previous) if self.rising && sample.throughput < previous * IMPROVEMENT => {
+                // The last worker didn't help, go back and stay there for a while
+                self.rising = false;
+                self.hold = HOLD;
+                current.saturating_sub(1).max(tune.min)
+            }
+            _ => {
+                self.rising = current < tune.max;
+                current.saturating_add(1).min(tune.max)
+            }
+        };
+        self.previous = Some(sample.throughput);
+        next
+    }
+}
+
+/// Tune the pool's concurrency while auto-tuning is enabled.
+pub(crate) async fn run(inner: Weak<Inner>, mut config: watch::Receiver<Option<AutoTune>>) {
+    let mut tuner = Tuner::default();
+    let mut last = Instant::now();
+    let mut restart = true;
+    loop {
+        let tune = *config.borrow_and_update();
+        let Some(tune) = tune else {
+            if config.changed().await.is_err() {
+                return;
+            }
+            restart = true;
+            continue;
+        };
+        if restart {
+            // Start measuring again with the new limits
+            let Some(inner) = inner.upgrade() else {
+                return;
+            };
+            inner.meter().sample(Duration::ZERO, false);
+            tuner = Tuner::default();
+            last = Instant::now();
+            restart = false;
+        }
+        tokio::select! {
+            () = tokio::time::sleep(tune.interval()) => {}
+            changed = config.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                restart = true;
+                continue;
+            }
+        }
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let (current, saturated) = inner.concurrency();
+        let sample = inner.meter().sample(last.elapsed(), saturated);
+        last = Instant::now();
+        let next = tuner.tick(&tune, current, &sample);
+        if next != current {
+            inner.set_concurrency(next).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(throughput: f64) -> Sample {
+        Sample {
+            throughput,
+            errors: 0,
+            latency: Some(Duration::from_millis(100)),
+            saturated: true,
+        }
+    }
+
+    #[test]
+    fn test_tune() {
+        let tune = AutoTune::new(2, 6);
+        let mut tuner = Tuner::default();
+
+        // More workers while throughput improves
+        assert_eq!(tuner.tick(&tune, 2, &sample(10.0)), 3);
+        assert_eq!(tuner.tick(&tune, 3, &sample(15.0)), 4);
+        // The last worker didn't help
+        assert_eq!(tuner.tick(&tune, 4, &sample(15.2)), 3);
+        for _ in 0..HOLD {
+            assert_eq!(tuner.tick(&tune, 3, &sample(15.0)), 3);
+        }
+        assert_eq!(tuner.tick(&tune, 3, &sample(15.0)), 4);
+
+        // Back off on errors and latency spikes, within the limits
+        let errors = Sample {
+            errors: 2,
+            ..sample(15.0)
+        };
+        assert_eq!(tuner.tick(&tune, 6, &errors), 5);
+        assert_eq!(tuner.tick(&tune, 2, &errors), 2);
+        let spike = Sample {
+            latency: Some(Duration::from_secs(1)),
+            ..sample(15.0)
+        };
+        assert_eq!(tuner.tick(&tune, 5, &spike), 4);
+
+        // Nothing to learn without enough downloads
+        let idle = Sample {
+            saturated: false,
+            ..sample(1.0)
+        };
+        let mut tuner = Tuner::default();
+        assert_eq!(tuner.tick(&tune, 4, &idle), 4);
+        assert_eq!(tuner.tick(&tune, 6, &sample(10.0)), 6);
+        assert_eq!(tuner.tick(&tune, 9, &sample(10.0)), 6);
+    }
+}
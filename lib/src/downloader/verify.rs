@@ -0,0 +1,134 @@
+use std::io::Read;
+
+use ring::digest::{Context, SHA256};
+
+use super::{error::DownloadError, partial::Partial, pool::DownloadKey, sink::Sink};
+
+/// Checks a download against the size and SHA-256 its key expects, as it streams in.
+pub(crate) struct Verifier {
+    size: Option<u64>,
+    sha256: Option<(Vec<u8>, Context)>,
+    received: u64,
+}
+
+impl Verifier {
+    pub fn new(key: &DownloadKey) -> Self {
+        Self {
+            size: key.size(),
+            sha256: key
+                .sha256()
+                .map(|hash| (hash.to_vec(), Context::new(&SHA256))),
+            received: 0,
+        }
+    }
+
+    /// Continue a download of `key` after the `offset` bytes already in its sink.
+    ///
+    /// Bytes resumed from a partial file are read back to hash them.
+    pub async fn resume(key: &DownloadKey, offset: u64) -> Result<Self, DownloadError> {
+        let mut verifier = Self::new(key);
+        verifier.received = offset;
+        if let (Some((_, context)), Sink::File(path), true) =
+            (&mut verifier.sha256, key.sink(), offset != 0)
+        {
+            let path = Partial::part_path(path);
+            let prefix = std::mem::replace(context, Context::new(&SHA256));
+            *context = tokio::task::spawn_blocking(move || hash_prefix(prefix, &path, offset))
+                .await
+                .map_err(|e| DownloadError::Io(e.to_string()))?
+                .map_err(|e| DownloadError::Io(e.to_string()))?;
+        }
+        Ok(verifier)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.received += data.len() as u64;
+        if let Some((_, context)) = &mut self.sha256 {
+            context.update(data);
+        }
+    }
+
+    /// Check everything has been received, and matches the expected hash.
+    pub fn verify(self) -> Result<(), DownloadError> {
+        if let Some(size) = self.size {
+            if size != self.received {
+                return Err(DownloadError::Verification(format!(
+                    "expected {size} bytes, received {}",
+                    self.received
+                )));
+            }
+        }
+        if let Some((expected, context)) = self.sha256 {
+            if context.finish().as_ref() != expected.as_slice() {
+                return Err(DownloadError::Verification(
+                    "SHA-256 does not match".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Feed the first `len` bytes of the file at `path` to `context`.
+fn hash_prefix(mut context: Context, path: &std::path::Path, len: u64) -> std::io::Result<Context> {
+    let mut file = std::fs::File::open(path)?.take(len);
+    let mut buf = vec![0; 256 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        context.update(&buf[..read]);
+    }
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::digest::digest;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_verify() {
+        let url = "https://example.com/mod.pbo".to_string();
+        let hash = digest(&SHA256, b"0123456789").as_ref().to_vec();
+        let key = DownloadKey::new(url.clone(), None)
+            .with_size(10)
+            .with_sha256(hash.clone());
+
+        let mut verifier = Verifier::new(&key);
+        verifier.update(b"01234");
+        verifier.update(b"56789");
+        assert_eq!(verifier.verify(), Ok(()));
+
+        let mut verifier = Verifier::new(&key);
+        verifier.update(b"01234");
+        assert!(matches!(
+            verifier.verify(),
+            Err(DownloadError::Verification(_))
+        ));
+
+        let mut verifier = Verifier::new(&key);
+        verifier.update(b"01234x6789");
+        assert!(matches!(
+            verifier.verify(),
+            Err(DownloadError::Verification(_))
+        ));
+
+        // Resuming hashes the bytes already written
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mod.pbo");
+        std::fs::write(Partial::part_path(&path), b"0123").unwrap();
+        let key = key.with_sink(Sink::File(path));
+        let mut verifier = Verifier::resume(&key, 4).await.unwrap();
+        verifier.update(b"456789");
+        assert_eq!(verifier.verify(), Ok(()));
+
+        // Nothing to check without an expected size or hash
+        let key = DownloadKey::new(url, None);
+        let mut verifier = Verifier::new(&key);
+        verifier.update(b"anything");
+        assert_eq!(verifier.verify(), Ok(()));
+    }
+}
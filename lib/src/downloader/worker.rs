@@ -1,123 +1,391 @@
-use std::time::Duration;
+use std::{
+    io::Write,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use reqwest::{Client, ClientBuilder};
 use tokio::sync::{
     mpsc::{Receiver, Sender},
     watch,
 };
 
-use super::pool::DownloadKey;
+use super::{
+    client::ClientConfig,
+    decode::Decoder,
+    error::{DownloadError, Timeout},
+    limiter::RateLimiter,
+    mirror::Mirrors,
+    partial::Partial,
+    pool::{Control, DownloadKey, PoolState},
+    retry::RetryPolicy,
+    sink::Output,
+    stall::Stall,
+    transport::{self, Request, Transports},
+    tune::Meter,
+    verify::Verifier,
+};
 
 pub struct Worker {
     /// Worker ID, used in update messages.
     id: u8,
 
-    client: Client,
+    /// Transports by URL scheme.
+    transports: watch::Receiver<Transports>,
+    /// Timeouts for stalled transfers.
+    config: watch::Receiver<ClientConfig>,
     /// Channel to send updates to the pool.
     update: Sender<Update>,
     /// Channel to receive commands from the pool.
     command: Receiver<Command>,
 
-    /// Current download speed limit.
-    rate_limit: watch::Receiver<Option<u64>>,
+    /// Limits the total download speed, shared with the other workers.
+    limiter: Arc<RateLimiter>,
+    /// Mirrors to spread requests across, shared with the other workers.
+    mirrors: Arc<Mirrors>,
+    /// Measures all workers for auto-tuning.
+    meter: Arc<Meter>,
+    /// Current retry policy.
+    retry: watch::Receiver<RetryPolicy>,
+    /// Whether the pool is running, paused or shutting down.
+    state: watch::Receiver<PoolState>,
 }
 
 impl Worker {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: u8,
         update: Sender<Update>,
         command: Receiver<Command>,
-        rate_limit: watch::Receiver<Option<u64>>,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        Ok(Self {
+        transports: watch::Receiver<Transports>,
+        config: watch::Receiver<ClientConfig>,
+        limiter: Arc<RateLimiter>,
+        mirrors: Arc<Mirrors>,
+        meter: Arc<Meter>,
+        retry: watch::Receiver<RetryPolicy>,
+        state: watch::Receiver<PoolState>,
+    ) -> Self {
+        Self {
             id,
-            client: ClientBuilder::new()
-                .timeout(Duration::from_secs(60 * 60))
-                .tcp_nodelay(true)
-                .build()?,
+            transports,
+            config,
             update,
             command,
 
-            rate_limit,
-        })
+            limiter,
+            mirrors,
+            meter,
+            retry,
+            state,
+        }
     }
 
     pub async fn run(&mut self) {
         while let Some(command) = self.command.recv().await {
             match command {
-                Command::Download(key) => {
-                    let mut output = Vec::new();
-                    let mut response = {
-                        let mut req = self.client.get(key.url());
-                        if let Some((start, end)) = key.range() {
-                            req = req.header("Range", format!("bytes={}-{}", start, end));
+                Command::Download(key, mut control) => {
+                    let _ = self
+                        .update
+                        .send(Update::Started {
+                            id: self.id,
+                            key: key.clone(),
+                        })
+                        .await;
+                    let update = match self.download(&key, &mut control).await {
+                        Ok(output) => Update::Done(self.id, key, output),
+                        Err(error) => {
+                            if *control.borrow() == Control::Cancelled {
+                                key.sink().discard();
+                            }
+                            Update::Failed(self.id, key, error)
                         }
-                        req.send().await.unwrap()
                     };
+                    let _ = self.update.send(update).await;
+                }
+                Command::Stop => {
+                    break;
+                }
+            }
+        }
+    }
 
-                    let total = response.content_length().unwrap_or(0);
-                    let mut downloaded = 0u64;
-                    let mut last_update = std::time::Instant::now();
-                    let mut last_sleep = std::time::Instant::now();
-                    let mut last_sleep_downloaded_since = 0u64;
-                    let mut last_downloaded = 0;
-
-                    while let Some(chunk) = response.chunk().await.unwrap() {
-                        downloaded += chunk.len() as u64;
-                        output.extend_from_slice(&chunk);
-
-                        if last_update.elapsed() > Duration::from_millis(500) {
-                            let speed = (downloaded - last_downloaded) as f64
-                                / last_update.elapsed().as_secs_f64();
-                            last_downloaded = downloaded;
-                            last_update = std::time::Instant::now();
-
-                            let _ = self
-                                .update
-                                .send(Update::Progress {
-                                    id: self.id,
-                                    key: key.clone(),
-                                    downloaded,
-                                    total,
-                                    speed,
-                                })
-                                .await;
-                        }
+    /// Download `key`, retrying according to the retry policy.
+    ///
+    /// A failed attempt fails over to a mirror that hasn't been tried yet straight away,
+    /// without counting against the retry policy.
+    async fn download(
+        &mut self,
+        key: &DownloadKey,
+        control: &mut watch::Receiver<Control>,
+    ) -> Result<Output, DownloadError> {
+        let mut attempt = 1;
+        let mut tried = Vec::new();
+        loop {
+            self.ready(control).await?;
+            let url = self.mirrors.pick(key.url(), &tried);
+            let (error, retry_after) = match self.attempt(key, &url, control).await {
+                Ok(output) => return Ok(output),
+                Err(failure) => failure,
+            };
+            tried.push(url);
+            let failover =
+                error != DownloadError::Cancelled && self.mirrors.has_untried(key.url(), &tried);
+            let policy = *self.retry.borrow();
+            if !failover && (!error.is_retryable() || attempt >= policy.max_attempts()) {
+                return Err(error);
+            }
+            let delay = if failover {
+                Duration::ZERO
+            } else {
+                policy.retry_delay(attempt, retry_after)
+            };
+            let _ = self
+                .update
+                .send(Update::Retrying {
+                    id: self.id,
+                    key: key.clone(),
+                    attempt,
+                    delay,
+                    error,
+                })
+                .await;
+            let sleep = tokio::time::sleep(delay);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    () = &mut sleep => break,
+                    _ = control.changed() => {}
+                    _ = self.state.changed() => {}
+                }
+                if self.stopped(control) {
+                    return Err(DownloadError::Cancelled);
+                }
+            }
+            if !failover {
+                attempt += 1;
+            }
+        }
+    }
 
-                        if let Some((_, end)) = key.range() {
-                            if downloaded >= end {
-                                break;
-                            }
-                        }
+    /// Has the download been cancelled, or the pool shut down.
+    fn stopped(&mut self, control: &mut watch::Receiver<Control>) -> bool {
+        *self.state.borrow_and_update() == PoolState::Shutdown
+            || *control.borrow_and_update() == Control::Cancelled
+            || self.state.has_changed().is_err()
+            || control.has_changed().is_err()
+    }
 
-                        let rate_limit = *self.rate_limit.borrow_and_update();
-                        if let Some(speed_limit) = rate_limit {
-                            let required_time =
-                                (downloaded - last_sleep_downloaded_since) * 1000 / speed_limit;
-                            let sleep_time = Duration::from_millis(required_time);
-                            if required_time > 100 && last_sleep.elapsed() < sleep_time {
-                                tokio::time::sleep(sleep_time - last_sleep.elapsed()).await;
-                                last_sleep = std::time::Instant::now();
-                                last_sleep_downloaded_since = downloaded;
-                            }
-                        }
+    /// Wait while the download or the pool is paused.
+    async fn ready(&mut self, control: &mut watch::Receiver<Control>) -> Result<(), DownloadError> {
+        loop {
+            if self.stopped(control) {
+                return Err(DownloadError::Cancelled);
+            }
+            if *self.state.borrow() == PoolState::Running && *control.borrow() == Control::Running {
+                return Ok(());
+            }
+            tokio::select! {
+                _ = control.changed() => {}
+                _ = self.state.changed() => {}
+            }
+        }
+    }
+
+    /// Make a single attempt at downloading `key` from `url`, the key's URL or one of its mirrors.
+    ///
+    /// On failure, also returns the delay the server asked for before retrying.
+    async fn attempt(
+        &mut self,
+        key: &DownloadKey,
+        url: &str,
+        control: &mut watch::Receiver<Control>,
+    ) -> Result<Output, (DownloadError, Option<Duration>)> {
+        let scheme = transport::scheme(url);
+        let transport = self
+            .transports
+            .borrow()
+            .get(&scheme)
+            .cloned()
+            .ok_or((DownloadError::UnsupportedScheme(scheme), None))?;
+        // A compressed download can't resume from the decompressed bytes
+        let partial = match key.encoding() {
+            Some(_) => None,
+            None => key.sink().partial(key.url()),
+        };
+        let fetch = match key.encoding() {
+            Some(encoding) => format!("{url}.{}", encoding.extension()),
+            None => url.to_string(),
+        };
+        let mut decoder = key
+            .encoding()
+            .map(Decoder::new)
+            .transpose()
+            .map_err(|e| (e, None))?;
+        let config = self.config.borrow().clone();
+        let started = Instant::now();
+        let open = transport.open(Request::new(
+            &fetch,
+            key.range(),
+            partial.as_ref().map_or(0, Partial::received),
+            partial.as_ref().and_then(Partial::validator),
+        ));
+        let response = tokio::time::timeout(config.connect_timeout() + config.read_timeout(), open)
+            .await
+            .unwrap_or_else(|_| Err(DownloadError::Timeout(Timeout::Response).into()))
+            .map_err(|e| {
+                self.mirrors.failure(url);
+                e.into_parts()
+            })?;
+        let latency = started.elapsed();
+        self.meter.latency(latency);
+
+        // Without resuming, the file changed or the server ignored the range
+        let offset = match partial {
+            Some(partial) if response.resumed() => partial.received(),
+            _ => 0,
+        };
+        let total = match key.range() {
+            Some((start, end)) => end - start + 1,
+            None => offset + response.length().unwrap_or(0),
+        };
+        let (mut body, validator) = response.into_parts();
+        let validator = validator.filter(|_| decoder.is_none());
+        let mut verifier = Verifier::resume(key, offset).await.map_err(|e| (e, None))?;
+        let mut writer = key
+            .sink()
+            .open(key.url(), validator, offset)
+            .map_err(|e| (DownloadError::Io(e.to_string()), None))?;
+        let mut downloaded = offset;
+        let mut last_update = std::time::Instant::now();
+        let mut last_downloaded = offset;
+        // Time spent waiting on the body, not on the rate limiter or while paused.
+        let mut receiving = Duration::ZERO;
+        let mut stall = Stall::new(&config, Instant::now());
+
+        loop {
+            let waiting = Instant::now();
+            let deadline = stall.deadline();
+            let next = tokio::select! {
+                chunk = body.chunk() => Some(chunk),
+                () = tokio::time::sleep_until(deadline.into()) => match stall.expired(Instant::now()) {
+                    Ok(()) => {
+                        receiving += waiting.elapsed();
+                        continue;
                     }
+                    Err(timeout) => Some(Err(DownloadError::Timeout(timeout))),
+                },
+                _ = control.changed() => None,
+                _ = self.state.changed() => None,
+            };
+            let Some(chunk) = next else {
+                // Paused or cancelled, the connection is kept open while paused.
+                let paused = Instant::now();
+                self.ready(control).await.map_err(|e| (e, None))?;
+                stall.skip(paused.elapsed());
+                continue;
+            };
+            receiving += waiting.elapsed();
+            let chunk = chunk
+                .and_then(|chunk| {
+                    let len = chunk.as_ref().map_or(0, |c| c.len() as u64);
+                    stall
+                        .received(len, Instant::now())
+                        .map_err(DownloadError::Timeout)?;
+                    Ok(chunk)
+                })
+                .map_err(|e| {
+                    self.mirrors.failure(url);
+                    (e, None)
+                })?;
+            let Some(chunk) = chunk else {
+                break;
+            };
+            let mut chunk = &chunk[..];
+            if key.range().is_some() {
+                // Don't write past the end of the range
+                chunk = &chunk[..(total - downloaded).min(chunk.len() as u64) as usize];
+            }
+            downloaded += chunk.len() as u64;
+            self.meter.received(chunk.len() as u64);
+            let data = match &mut decoder {
+                Some(decoder) => decoder.decode(chunk).map_err(|e| {
+                    self.mirrors.failure(url);
+                    (e, None)
+                })?,
+                None => chunk,
+            };
+            verifier.update(data);
+            writer
+                .write_all(data)
+                .map_err(|e| (DownloadError::Io(e.to_string()), None))?;
+            let limited = Instant::now();
+            self.limiter.acquire(chunk.len() as u64).await;
+            stall.skip(limited.elapsed());
 
-                    self.update
-                        .send(Update::Done(self.id, key, output))
-                        .await
-                        .unwrap();
-                }
-                Command::Stop => {
-                    break;
-                }
+            if last_update.elapsed() > Duration::from_millis(500) {
+                writer
+                    .checkpoint()
+                    .map_err(|e| (DownloadError::Io(e.to_string()), None))?;
+                let speed =
+                    (downloaded - last_downloaded) as f64 / last_update.elapsed().as_secs_f64();
+                last_downloaded = downloaded;
+                last_update = std::time::Instant::now();
+
+                let _ = self
+                    .update
+                    .send(Update::Progress {
+                        id: self.id,
+                        key: key.clone(),
+                        downloaded,
+                        total,
+                        speed,
+                    })
+                    .await;
             }
+
+            if key.range().is_some() && downloaded >= total {
+                break;
+            }
+        }
+        if total != 0 && downloaded < total {
+            // The connection was closed early, or the mirror has a truncated file
+            self.mirrors.failure(url);
+            return Err((
+                DownloadError::Connection(format!(
+                    "Body ended after {downloaded} of {total} bytes"
+                )),
+                None,
+            ));
         }
+        if let Some(decoder) = decoder {
+            let rest = decoder.finish().map_err(|e| {
+                self.mirrors.failure(url);
+                (e, None)
+            })?;
+            verifier.update(&rest);
+            writer
+                .write_all(&rest)
+                .map_err(|e| (DownloadError::Io(e.to_string()), None))?;
+        }
+        if let Err(error) = verifier.verify() {
+            // Start over next time, rather than resume after corrupt bytes
+            drop(writer);
+            key.sink().discard();
+            self.mirrors.failure(url);
+            return Err((error, None));
+        }
+        self.mirrors
+            .success(url, latency, downloaded - offset, receiving);
+
+        writer
+            .finish()
+            .map_err(|e| (DownloadError::Io(e.to_string()), None))
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum Update {
+    /// A worker took the download from the queue.
+    Started { id: u8, key: DownloadKey },
     Progress {
         id: u8,
         key: DownloadKey,
@@ -125,36 +393,51 @@ pub enum Update {
         total: u64,
         speed: f64,
     },
-    Done(u8, DownloadKey, Vec<u8>),
+    /// An attempt failed, and the download will be tried again after `delay`.
+    Retrying {
+        id: u8,
+        key: DownloadKey,
+        attempt: u32,
+        delay: Duration,
+        error: DownloadError,
+    },
+    /// The download has finished, with a cheap handle to the result.
+    Done(u8, DownloadKey, Output),
+    /// The download failed, and will not be retried.
+    Failed(u8, DownloadKey, DownloadError),
 }
 
 impl Update {
     pub fn id(&self) -> u8 {
         match self {
-            Self::Progress { id, .. } => *id,
-            Self::Done(id, _, _) => *id,
+            Self::Started { id, .. } | Self::Progress { id, .. } | Self::Retrying { id, .. } => *id,
+            Self::Done(id, _, _) | Self::Failed(id, _, _) => *id,
         }
     }
 
     pub fn key(&self) -> &DownloadKey {
         match self {
-            Self::Progress { key, .. } => key,
-            Self::Done(_, key, _) => key,
+            Self::Started { key, .. } | Self::Progress { key, .. } | Self::Retrying { key, .. } => {
+                key
+            }
+            Self::Done(_, key, _) | Self::Failed(_, key, _) => key,
         }
     }
 
     pub fn url(&self) -> &str {
-        match self {
-            Self::Progress { key, .. } => key.url(),
-            Self::Done(_, key, _) => key.url(),
-        }
+        self.key().url()
+    }
+
+    /// Is this the last update for the download.
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Done(..) | Self::Failed(..))
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum Command {
-    /// Download a blob
-    Download(DownloadKey),
+    /// Download a blob, following the requested state
+    Download(DownloadKey, watch::Receiver<Control>),
     /// Stop the worker
     Stop,
 }
@@ -162,15 +445,44 @@ pub enum Command {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        downloader::host::Hosts,
+        testing::{MockRepository, MockServer},
+    };
 
     #[tokio::test]
     async fn test_download() {
         let (update_tx, mut update_rx) = tokio::sync::mpsc::channel(1);
         let (command_tx, command_rx) = tokio::sync::mpsc::channel(1);
-        let (rate_limit_tx, rate_limit_rx) = tokio::sync::watch::channel(None);
-        let mut worker = Worker::new(0, update_tx, command_rx, rate_limit_rx).unwrap();
+        let limiter = Arc::new(RateLimiter::new(None));
+        let (_retry_tx, retry_rx) = tokio::sync::watch::channel(RetryPolicy::default());
+        let (_state_tx, state_rx) = tokio::sync::watch::channel(PoolState::Running);
+        let (_control_tx, control_rx) = tokio::sync::watch::channel(Control::Running);
+        let (_hosts_tx, hosts_rx) = tokio::sync::watch::channel(Hosts::new());
+        let (_client_tx, client_rx) =
+            tokio::sync::watch::channel(ClientConfig::default().build().unwrap());
+        let (_config_tx, config_rx) = tokio::sync::watch::channel(ClientConfig::default());
+        let (_transports_tx, transports_rx) = tokio::sync::watch::channel(transport::defaults(
+            client_rx,
+            config_rx.clone(),
+            hosts_rx,
+        ));
+        let mut worker = Worker::new(
+            0,
+            update_tx,
+            command_rx,
+            transports_rx,
+            config_rx,
+            limiter.clone(),
+            Arc::new(Mirrors::default()),
+            Arc::new(Meter::default()),
+            retry_rx,
+            state_rx,
+        );
 
-        let url = String::from("https://images.unsplash.com/photo-1650409476524-7eb2f71b6cc8");
+        let repository = MockRepository::new("test").with_random_file("@mod/image.jpg", 512 * 1024);
+        let server = MockServer::serve(&repository).await.unwrap();
+        let url = server.url("@mod/image.jpg");
 
         let handle = tokio::spawn(async move {
             worker.run().await;
@@ -183,15 +495,16 @@ mod tests {
             human_bytes::human_bytes(RATE_LIMIT as f64)
         );
 
-        rate_limit_tx.send(Some(RATE_LIMIT)).unwrap();
+        limiter.set_rate(Some(RATE_LIMIT));
 
         command_tx
-            .send(Command::Download(DownloadKey::new(url, None)))
+            .send(Command::Download(DownloadKey::new(url, None), control_rx))
             .await
             .unwrap();
 
         while let Some(update) = update_rx.recv().await {
             match update {
+                Update::Started { .. } => {}
                 Update::Progress {
                     id: _,
                     key: _,
@@ -205,10 +518,16 @@ mod tests {
                         human_bytes::human_bytes(speed)
                     );
                 }
-                Update::Done(_, _, _) => {
+                Update::Retrying { error, .. } => println!("Retrying: {error}"),
+                Update::Done(_, _, output) => {
+                    let Output::Memory(content) = output else {
+                        panic!("Download was not kept in memory");
+                    };
+                    assert_eq!(Some(&content[..]), repository.file("@mod/image.jpg"));
                     command_tx.send(Command::Stop).await.unwrap();
                     break;
                 }
+                Update::Failed(_, _, error) => panic!("Download failed: {error}"),
             }
         }
 
@@ -1,3 +1,6 @@
 pub mod config;
 pub mod downloader;
 pub mod repo;
+pub mod sync;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
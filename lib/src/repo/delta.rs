@@ -93,7 +93,7 @@ fn check_layer(old: &Layer, new: &Layer) -> HashMap<String, FileDelta> {
         }
     }
     for file in new.files() {
-        if !changed.contains_key(file.name()) {
+        if !old.files().iter().any(|of| of.name() == file.name()) {
             changed.insert(file.name().to_string(), FileDelta::New);
         }
     }
@@ -107,7 +107,7 @@ fn check_layer(old: &Layer, new: &Layer) -> HashMap<String, FileDelta> {
         }
     }
     for layer in new.layers() {
-        if !changed.contains_key(layer.name()) {
+        if !old.layers().iter().any(|ol| ol.name() == layer.name()) {
             changed.insert(layer.name().to_string(), FileDelta::New);
         }
     }
@@ -0,0 +1,264 @@
+use std::{
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Files smaller than this are not worth compressing.
+const MIN_SIZE: u64 = 4 * 1024;
+/// A variant must be at most this share of the original to be published.
+const MAX_RATIO: f64 = 0.9;
+/// Variants are compressed once and downloaded many times, so spend the time.
+const ZSTD_LEVEL: i32 = 19;
+/// Bytes from the middle of a larger file compressed first, to skip files that won't
+/// compress without compressing all of them.
+const SAMPLE: u64 = 256 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// A compression a file can be published with, next to the original.
+pub enum Encoding {
+    /// Zstandard, published as `<file>.zst`.
+    Zstd,
+    /// Gzip, published as `<file>.gz`.
+    Gzip,
+}
+
+impl Encoding {
+    /// Every encoding, whether or not a repository publishes it.
+    pub const ALL: [Self; 2] = [Self::Zstd, Self::Gzip];
+
+    #[must_use]
+    /// Gets the extension added to the name of the original file.
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Zstd => "zst",
+            Self::Gzip => "gz",
+        }
+    }
+
+    #[must_use]
+    /// Gets the path of the variant of the file at `path`.
+    pub fn path(self, path: &Path) -> PathBuf {
+        let mut path = path.as_os_str().to_owned();
+        path.push(".");
+        path.push(self.extension());
+        PathBuf::from(path)
+    }
+
+    /// Publish the file at `path` compressed next to it, if that makes it small enough.
+    ///
+    /// A variant left from an earlier run is reused if it is newer than the file and was
+    /// compressed from as many bytes, or removed if it is no longer worth publishing.
+    pub fn compress(self, path: &Path) -> Result<Option<Variant>, String> {
+        let variant = self.path(path);
+        let metadata = std::fs::metadata(path).map_err(|e| e.to_string())?;
+        let size = metadata.len();
+        if let Ok(existing) = std::fs::metadata(&variant) {
+            let newer = match (existing.modified(), metadata.modified()) {
+                (Ok(variant), Ok(original)) => variant >= original,
+                _ => false,
+            };
+            if newer && self.compressed_from(&variant, size).unwrap_or(false) {
+                return Ok(Some(Variant::new(self, existing.len())));
+            }
+        }
+        if size < MIN_SIZE || !self.worth_it(path, size).map_err(|e| e.to_string())? {
+            let _ = std::fs::remove_file(&variant);
+            return Ok(None);
+        }
+        let input = BufReader::new(std::fs::File::open(path).map_err(|e| e.to_string())?);
+        let output = BufWriter::new(std::fs::File::create(&variant).map_err(|e| e.to_string())?);
+        self.encode(input, size, output)
+            .and_then(|mut output| output.flush())
+            .map_err(|e| e.to_string())?;
+        let compressed = std::fs::metadata(&variant)
+            .map_err(|e| e.to_string())?
+            .len();
+        if compressed as f64 > size as f64 * MAX_RATIO {
+            let _ = std::fs::remove_file(&variant);
+            return Ok(None);
+        }
+        Ok(Some(Variant::new(self, compressed)))
+    }
+
+    /// Does a sample of the file at `path` compress well enough, always for small files.
+    fn worth_it(self, path: &Path, size: u64) -> std::io::Result<bool> {
+        if size <= SAMPLE * 2 {
+            return Ok(true);
+        }
+        let mut file = std::fs::File::open(path)?;
+        file.seek(SeekFrom::Start((size - SAMPLE) / 2))?;
+        let compressed = self.encode(file.take(SAMPLE), SAMPLE, Vec::new())?;
+        Ok(compressed.len() as f64 <= SAMPLE as f64 * MAX_RATIO)
+    }
+
+    /// Was the variant at `path` compressed from `size` bytes, as recorded in it.
+    ///
+    /// Gzip only records the size modulo 2^32.
+    fn compressed_from(self, path: &Path, size: u64) -> std::io::Result<bool> {
+        let mut file = std::fs::File::open(path)?;
+        match self {
+            Self::Zstd => {
+                // The largest frame header
+                let mut header = Vec::with_capacity(18);
+                file.take(18).read_to_end(&mut header)?;
+                Ok(zstd::zstd_safe::get_frame_content_size(&header).is_ok_and(|s| s == Some(size)))
+            }
+            Self::Gzip => {
+                let mut trailer = [0; 4];
+                file.seek(SeekFrom::End(-4))?;
+                file.read_exact(&mut trailer)?;
+                Ok(u64::from(u32::from_le_bytes(trailer)) == size & 0xffff_ffff)
+            }
+        }
+    }
+
+    /// Compress the `size` bytes from `input` to `output`.
+    fn encode<W: Write>(self, mut input: impl Read, size: u64, output: W) -> std::io::Result<W> {
+        match self {
+            Self::Zstd => {
+                let mut encoder = zstd::Encoder::new(output, ZSTD_LEVEL)?;
+                // Recorded in the frame, to reuse the variant while the size matches
+                encoder.set_pledged_src_size(Some(size))?;
+                std::io::copy(&mut input, &mut encoder)?;
+                encoder.finish()
+            }
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(output, flate2::Compression::best());
+                std::io::copy(&mut input, &mut encoder)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    #[must_use]
+    /// Checks if `name` is the variant of a file named `original`.
+    pub fn is_variant(self, name: &str, original: &str) -> bool {
+        name.strip_prefix(original)
+            .and_then(|rest| rest.strip_prefix('.'))
+            == Some(self.extension())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// A compressed copy of a file, published next to it.
+pub struct Variant {
+    #[serde(rename = "e")]
+    /// How the file is compressed.
+    encoding: Encoding,
+    #[serde(rename = "s")]
+    /// The size of the compressed file.
+    size: u64,
+}
+
+impl Variant {
+    #[must_use]
+    /// Creates a new variant.
+    pub const fn new(encoding: Encoding, size: u64) -> Self {
+        Self { encoding, size }
+    }
+
+    #[must_use]
+    /// Gets how the file is compressed.
+    pub const fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    #[must_use]
+    /// Gets the size of the compressed file.
+    pub const fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+
+    use super::*;
+    use crate::{
+        downloader::DownloadKey,
+        repo::{File, Layer},
+    };
+
+    #[test]
+    fn test_compress() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.cpp");
+        std::fs::write(&path, "class CfgPatches {};\n".repeat(1000)).unwrap();
+        for encoding in Encoding::ALL {
+            let variant = encoding.compress(&path).unwrap().unwrap();
+            assert_eq!(variant.encoding(), encoding);
+            assert_eq!(
+                std::fs::metadata(encoding.path(&path)).unwrap().len(),
+                variant.size()
+            );
+        }
+
+        // A variant newer than the file is reused, an older one compressed again
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        for encoding in Encoding::ALL {
+            let variant = std::fs::File::options()
+                .write(true)
+                .open(encoding.path(&path))
+                .unwrap();
+            let later = modified + std::time::Duration::from_secs(10);
+            variant.set_modified(later).unwrap();
+            assert!(encoding.compress(&path).unwrap().is_some());
+            let metadata = std::fs::metadata(encoding.path(&path)).unwrap();
+            assert_eq!(metadata.modified().unwrap(), later);
+
+            let earlier = modified - std::time::Duration::from_secs(10);
+            variant.set_modified(earlier).unwrap();
+            assert!(encoding.compress(&path).unwrap().is_some());
+            let metadata = std::fs::metadata(encoding.path(&path)).unwrap();
+            assert_ne!(metadata.modified().unwrap(), earlier);
+        }
+        assert!(Encoding::Zstd.is_variant("config.cpp.zst", "config.cpp"));
+        assert!(!Encoding::Gzip.is_variant("config.cpp.zst", "config.cpp"));
+
+        // Random bytes don't compress, the stale variant is removed
+        let random = (0..8192).map(|_| fastrand::u8(..)).collect::<Vec<_>>();
+        std::fs::write(&path, random).unwrap();
+        assert_eq!(Encoding::Zstd.compress(&path).unwrap(), None);
+        assert!(!Encoding::Zstd.path(&path).exists());
+
+        // A large file is skipped by its sample, even if its start compresses well
+        let mut large = vec![0; SAMPLE as usize];
+        large.extend((0..SAMPLE * 2).map(|_| fastrand::u8(..)));
+        std::fs::write(&path, &large).unwrap();
+        assert!(!Encoding::Zstd.worth_it(&path, large.len() as u64).unwrap());
+        assert_eq!(Encoding::Zstd.compress(&path).unwrap(), None);
+
+        // PBOs are checked by the hash of their bytes, compressed or not
+        let content = "class CfgPatches {};\n".repeat(1000);
+        std::fs::write(&path, &content).unwrap();
+        let hash = ring::digest::digest(&ring::digest::SHA256, content.as_bytes());
+        let mut pbo = File::new_pbo(
+            "config.pbo".to_string(),
+            content.len() as u64,
+            IndexMap::new(),
+            Vec::new(),
+            vec![0; 32],
+            hash.as_ref().to_vec(),
+        );
+        let key = DownloadKey::file(String::new(), &pbo, &[Encoding::Zstd]);
+        assert_eq!(key.sha256(), Some(hash.as_ref()));
+        pbo.compress(&path, &[Encoding::Zstd]).unwrap();
+        let key = DownloadKey::file(String::new(), &pbo, &[Encoding::Zstd]);
+        assert_eq!(key.encoding(), Some(Encoding::Zstd));
+        assert_eq!(key.sha256(), Some(hash.as_ref()));
+
+        // Variants of an encoding no longer published are not published as files
+        let folder = dir.path().join("addons");
+        std::fs::create_dir(&folder).unwrap();
+        std::fs::write(folder.join("config.cpp"), &content).unwrap();
+        Encoding::Gzip.compress(&folder.join("config.cpp")).unwrap();
+        let layer = Layer::from_folder(folder, &[Encoding::Zstd]).unwrap();
+        let names = layer.files().iter().map(File::name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["config.cpp"]);
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     io::{BufReader, Read},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use hemtt_pbo::ReadablePbo;
@@ -8,7 +8,7 @@ use indexmap::IndexMap;
 use ring::digest::{Context, SHA256};
 use serde::{Deserialize, Serialize};
 
-use super::sha256_digest;
+use super::{sha256_digest, Encoding, Variant};
 
 #[derive(Debug, Serialize, Deserialize)]
 /// A file.
@@ -25,6 +25,9 @@ pub enum File {
         #[serde(rename = "h")]
         /// The hash of the file.
         hash: Vec<u8>,
+        #[serde(rename = "c", default, skip_serializing_if = "Vec::is_empty")]
+        /// Compressed copies published next to the file.
+        variants: Vec<Variant>,
     },
     #[serde(rename = "p")]
     /// A PBO file.
@@ -44,6 +47,12 @@ pub enum File {
         #[serde(rename = "h")]
         /// The hash of the file.
         hash: Vec<u8>,
+        #[serde(rename = "c", default)]
+        /// Compressed copies published next to the file.
+        variants: Vec<Variant>,
+        #[serde(rename = "b", default, skip_serializing_if = "Vec::is_empty")]
+        /// The SHA-256 of the bytes of the file, its own hash being of its contents.
+        sha256: Vec<u8>,
     },
 }
 
@@ -51,7 +60,12 @@ impl File {
     #[must_use]
     /// Creates a new generic file.
     pub const fn new_generic(name: String, size: u64, hash: Vec<u8>) -> Self {
-        Self::Generic { name, size, hash }
+        Self::Generic {
+            name,
+            size,
+            hash,
+            variants: Vec::new(),
+        }
     }
 
     #[must_use]
@@ -62,6 +76,7 @@ impl File {
         props: IndexMap<String, String>,
         parts: Vec<Part>,
         hash: Vec<u8>,
+        sha256: Vec<u8>,
     ) -> Self {
         Self::Pbo {
             name,
@@ -69,6 +84,8 @@ impl File {
             props,
             parts,
             hash,
+            variants: Vec::new(),
+            sha256,
         }
     }
 
@@ -80,6 +97,14 @@ impl File {
         }
     }
 
+    #[must_use]
+    /// Gets the size of the file.
+    pub const fn size(&self) -> u64 {
+        match self {
+            Self::Pbo { size, .. } | Self::Generic { size, .. } => *size,
+        }
+    }
+
     #[must_use]
     /// Gets the hash of the file.
     pub fn hash(&self) -> &[u8] {
@@ -88,6 +113,46 @@ impl File {
         }
     }
 
+    #[must_use]
+    /// Gets the SHA-256 of the bytes of the file, empty if it wasn't published.
+    pub fn sha256(&self) -> &[u8] {
+        match self {
+            Self::Generic { hash, .. } => hash,
+            Self::Pbo { sha256, .. } => sha256,
+        }
+    }
+
+    #[must_use]
+    /// Gets the compressed copies published next to the file.
+    pub fn variants(&self) -> &[Variant] {
+        match self {
+            Self::Pbo { variants, .. } | Self::Generic { variants, .. } => variants,
+        }
+    }
+
+    #[must_use]
+    /// Gets the smallest compressed copy in one of the `accepted` encodings.
+    pub fn variant(&self, accepted: &[Encoding]) -> Option<&Variant> {
+        self.variants()
+            .iter()
+            .filter(|v| accepted.contains(&v.encoding()))
+            .min_by_key(|v| v.size())
+    }
+
+    /// Publish the file at `path` with each of `encodings`, where that makes it smaller
+    pub fn compress(&mut self, path: &Path, encodings: &[Encoding]) -> Result<(), String> {
+        let mut compressed = Vec::new();
+        for encoding in encodings {
+            if let Some(variant) = encoding.compress(path)? {
+                compressed.push(variant);
+            }
+        }
+        match self {
+            Self::Pbo { variants, .. } | Self::Generic { variants, .. } => *variants = compressed,
+        }
+        Ok(())
+    }
+
     /// Create a file
     pub fn from(path: PathBuf) -> Result<Self, String> {
         let name = path.file_name().unwrap().to_str().unwrap().to_string();
@@ -108,11 +173,23 @@ impl File {
         } else {
             path
         };
+        let pbo = path.extension() == Some(std::ffi::OsStr::new("pbo"));
+        Self::read(&path, pbo)
+    }
+
+    /// Hash the file at `path` the same way as this file, whatever its name
+    pub(crate) fn hash_of(&self, path: &Path) -> Result<Vec<u8>, String> {
+        Self::read(path, matches!(self, Self::Pbo { .. })).map(|file| file.hash().to_vec())
+    }
+
+    /// Read the file at `path`, as a PBO or a generic file
+    fn read(path: &Path, pbo: bool) -> Result<Self, String> {
         let name = path.file_name().unwrap().to_str().unwrap().to_string();
-        let input = std::fs::File::open(&path).map_err(|e| e.to_string())?;
-        let size = input.metadata().unwrap().len();
-        if path.extension() == Some(std::ffi::OsStr::new("pbo")) {
-            let mut pbo = ReadablePbo::from(BufReader::new(input)).unwrap();
+        let input = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        let size = input.metadata().map_err(|e| e.to_string())?.len();
+        if pbo {
+            let mut pbo = ReadablePbo::from(BufReader::new(input))
+                .map_err(|e| format!("Failed to read `{}`: {e}", path.display()))?;
             let mut parts = Vec::new();
             let mut hash = Context::new(&SHA256);
             for prop in pbo.properties() {
@@ -121,7 +198,10 @@ impl File {
             }
             for file in pbo.files_sorted() {
                 hash.update(file.filename().as_bytes());
-                let mut reader = pbo.file(file.filename()).unwrap().unwrap();
+                let mut reader = pbo
+                    .file(file.filename())
+                    .map_err(|e| e.to_string())?
+                    .ok_or_else(|| missing(path, file.filename()))?;
                 let mut buffer = [0; 1024];
                 let mut file_hash = Context::new(&SHA256);
                 loop {
@@ -136,7 +216,10 @@ impl File {
                 parts.push(Part {
                     name: file.filename().to_string(),
                     hash: file_hash,
-                    offset: pbo.file_offset(file.filename()).unwrap().unwrap(),
+                    offset: pbo
+                        .file_offset(file.filename())
+                        .map_err(|e| e.to_string())?
+                        .ok_or_else(|| missing(path, file.filename()))?,
                 })
             }
             Ok(Self::Pbo {
@@ -145,15 +228,27 @@ impl File {
                 props: pbo.properties().to_owned(),
                 parts,
                 hash: hash.finish().as_ref().to_vec(),
+                variants: Vec::new(),
+                // Checked while downloading, before the contents can be read
+                sha256: sha256_digest(BufReader::new(
+                    std::fs::File::open(path).map_err(|e| e.to_string())?,
+                ))?
+                .as_ref()
+                .to_vec(),
             })
         } else {
             let reader = BufReader::new(input);
             let hash = sha256_digest(reader)?.as_ref().to_vec();
-            Ok(Self::Generic { name, size, hash })
+            Ok(Self::new_generic(name, size, hash))
         }
     }
 }
 
+/// A file listed in the header of a PBO that can't be read from it
+fn missing(path: &Path, name: &str) -> String {
+    format!("`{}` is missing `{name}`", path.display())
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 /// A part of a PBO file.
 pub struct Part {
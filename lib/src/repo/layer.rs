@@ -3,7 +3,7 @@ use std::path::PathBuf;
 use ring::digest::{Context, SHA256};
 use serde::{Deserialize, Serialize};
 
-use super::File;
+use super::{Encoding, File};
 
 #[derive(Debug, Serialize, Deserialize)]
 /// A layer of a mod. Basically a directory.
@@ -67,8 +67,34 @@ impl Layer {
         &self.hash
     }
 
-    /// Create a layer from a folder
-    pub fn from_folder(path: PathBuf) -> Result<Self, String> {
+    #[must_use]
+    /// Gets the total size of all files in the layer and its sublayers
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(File::size).sum::<u64>()
+            + self.layers.iter().map(Self::size).sum::<u64>()
+    }
+
+    #[must_use]
+    /// Finds a file by its path relative to this layer, using `/` as separator
+    pub fn file(&self, path: &str) -> Option<&File> {
+        match path.rsplit_once('/') {
+            Some((parent, name)) => self.layer(parent)?.file(name),
+            None => self.files.iter().find(|f| f.name() == path),
+        }
+    }
+
+    #[must_use]
+    /// Finds a sublayer by its path relative to this layer, using `/` as separator
+    pub fn layer(&self, path: &str) -> Option<&Self> {
+        let (name, rest) = path
+            .split_once('/')
+            .map_or((path, None), |(n, r)| (n, Some(r)));
+        let layer = self.layers.iter().find(|l| l.name() == name)?;
+        rest.map_or(Some(layer), |rest| layer.layer(rest))
+    }
+
+    /// Create a layer from a folder, publishing its files with each of `encodings`
+    pub fn from_folder(path: PathBuf, encodings: &[Encoding]) -> Result<Self, String> {
         let name = path.file_name().unwrap().to_str().unwrap().to_string();
         let path = if name != name.to_lowercase() {
             let lower_path = {
@@ -92,7 +118,7 @@ impl Layer {
             return Err(format!("Failed to read_dir on `{}`", path.display()));
         };
         let mut layers = Vec::new();
-        let mut files = Vec::new();
+        let mut paths = Vec::new();
         for entry in entries {
             let Ok(entry) = entry else {
                 return Err(format!("Invalid entry: {entry:?}"));
@@ -102,10 +128,31 @@ impl Layer {
                 .expect("Failed to determine file type")
                 .is_dir()
             {
-                layers.push(Self::from_folder(entry.path())?);
+                layers.push(Self::from_folder(entry.path(), encodings)?);
             } else {
-                files.push(File::from(entry.path())?);
+                paths.push(entry.path());
+            }
+        }
+        // Variants published by an earlier run are not files of the mod, even once the
+        // repository stops publishing their encoding
+        let names = paths
+            .iter()
+            .filter_map(|p| p.file_name()?.to_str().map(str::to_lowercase))
+            .collect::<Vec<_>>();
+        paths.retain(|p| {
+            let name = p.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            let name = name.to_lowercase();
+            !Encoding::ALL
+                .iter()
+                .any(|e| names.iter().any(|original| e.is_variant(&name, original)))
+        });
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let mut file = File::from(path.clone())?;
+            if !encodings.is_empty() {
+                file.compress(&path.with_file_name(file.name()), encodings)?;
             }
+            files.push(file);
         }
 
         Ok(Self::new(
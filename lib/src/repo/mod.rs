@@ -6,6 +6,7 @@
 
 mod delta;
 mod dlc;
+mod encoding;
 mod file;
 mod layer;
 mod pack;
@@ -23,6 +24,7 @@ use std::{
 
 pub use delta::{FileDelta, ModDelta};
 pub use dlc::DLC;
+pub use encoding::{Encoding, Variant};
 pub use file::File;
 use indicatif::{ProgressBar, ProgressStyle};
 pub use layer::Layer;
@@ -36,6 +38,12 @@ pub use unit::Unit;
 
 use crate::config::Config;
 
+/// The version of the repository spec written by this version of hermes.
+///
+/// 2 added the mirrors of the unit, and the variants and byte hash of each file. Older
+/// clients can't read them, while a repository of version 1 reads as one without any.
+const VERSION: u8 = 2;
+
 #[derive(Debug, Serialize, Deserialize)]
 /// A configuration file for a hermes repository.
 pub struct Repository {
@@ -77,7 +85,7 @@ impl Repository {
             hash.update(m.hash());
         }
         Self {
-            version: 1,
+            version: VERSION,
             unit,
             mods,
             packs,
@@ -105,12 +113,41 @@ impl Repository {
         &self.mods
     }
 
+    #[must_use]
+    /// Gets a mod by name.
+    pub fn find_mod(&self, name: &str) -> Option<&Mod> {
+        self.mods.iter().find(|m| m.name() == name)
+    }
+
     #[must_use]
     /// Gets the packs in the repository.
     pub const fn packs(&self) -> &HashMap<String, Pack> {
         &self.packs
     }
 
+    #[must_use]
+    /// Gets the mods in a pack, resolving `*` and `-@mod` entries
+    pub fn pack_mods(&self, pack: &str) -> Option<Vec<&Mod>> {
+        let pack = self.packs.get(pack)?;
+        let mut mods: Vec<&Mod> = Vec::new();
+        for m in pack.mods() {
+            if m == "*" {
+                for m in &self.mods {
+                    if !mods.iter().any(|pm| pm.name() == m.name()) {
+                        mods.push(m);
+                    }
+                }
+            } else if let Some(name) = m.strip_prefix('-') {
+                mods.retain(|pm| pm.name() != name);
+            } else if let Some(m) = self.find_mod(m) {
+                if !mods.iter().any(|pm| pm.name() == m.name()) {
+                    mods.push(m);
+                }
+            }
+        }
+        Some(mods)
+    }
+
     #[must_use]
     /// Gets the servers in the repository.
     pub fn servers(&self) -> &[Server] {
@@ -146,7 +183,7 @@ impl Repository {
     /// Read a repo from a MessagePack blob
     pub fn from_blob(source: &[u8]) -> Result<Self, String> {
         let version = source[0];
-        if version != 1 {
+        if version == 0 || version > VERSION {
             return Err(format!("Unsupported Version: {version}"));
         }
         let read = BufReader::new(&source[33..]);
@@ -192,6 +229,7 @@ impl Repository {
         let pb = ProgressBar::new(mods_to_scan.len() as u64).with_style(style);
         let mods = RwLock::new(Vec::new());
         let active = RwLock::new(HashSet::new());
+        let encodings = config.compress().to_vec();
         #[allow(clippy::significant_drop_tightening)]
         // I believe this is a false positive, check later when this is out of the nursery
         mods_to_scan.par_iter().for_each(|m| {
@@ -205,7 +243,7 @@ impl Repository {
                     .collect::<Vec<_>>())
                 .join(","),
             );
-            let obj = Mod::from_folder(m).unwrap();
+            let obj = Mod::from_folder(m, &encodings).unwrap();
             mods.write().unwrap().push(obj);
             active.write().unwrap().remove(m);
             pb.set_message(
@@ -267,13 +305,19 @@ impl Mod {
         self.root.hash()
     }
 
-    /// Create a mod from a folder
-    pub fn from_folder(name: &str) -> Result<Self, String> {
+    #[must_use]
+    /// Get the total size of all files in the mod
+    pub fn size(&self) -> u64 {
+        self.root.size()
+    }
+
+    /// Create a mod from a folder, publishing its files with each of `encodings`
+    pub fn from_folder(name: &str, encodings: &[Encoding]) -> Result<Self, String> {
         let path = PathBuf::from(name);
         if !path.exists() {
             return Err(format!("No mod folder `{name}`"));
         }
-        let root = Layer::from_folder(path)?;
+        let root = Layer::from_folder(path, encodings)?;
         Ok(Self {
             name: name.to_string(),
             root,
@@ -295,3 +339,37 @@ fn sha256_digest<R: std::io::Read>(mut reader: R) -> Result<Digest, String> {
 
     Ok(context.finish())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blob() {
+        let unit = Unit::new("test".to_string(), None, Vec::new());
+        let file = File::new_generic("mod.cpp".to_string(), 1, vec![0]);
+        let root = Layer::new("@a".to_string(), vec![file], Vec::new());
+        let repository = Repository::new(
+            unit,
+            vec![Mod::new("@a".to_string(), root)],
+            HashMap::new(),
+            Vec::new(),
+            0,
+        );
+        let mut blob = repository.to_blob();
+        assert_eq!(blob[0], VERSION);
+        assert_eq!(
+            Repository::from_blob(&blob).unwrap().hash(),
+            repository.hash()
+        );
+
+        // A repository without the fields added since reads the same as version 1
+        blob[0] = 1;
+        assert_eq!(Repository::from_blob(&blob).unwrap().mods().len(), 1);
+        blob[0] = VERSION + 1;
+        assert_eq!(
+            Repository::from_blob(&blob).err(),
+            Some(format!("Unsupported Version: {}", VERSION + 1))
+        );
+    }
+}
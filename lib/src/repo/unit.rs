@@ -5,9 +5,18 @@ use serde::{Deserialize, Serialize};
 pub struct Unit {
     name: String,
     id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Base URLs the repository is served from, each with the same files
+    mirrors: Vec<String>,
 }
 
 impl Unit {
+    #[must_use]
+    /// Creates a new unit.
+    pub const fn new(name: String, id: Option<String>, mirrors: Vec<String>) -> Self {
+        Self { name, id, mirrors }
+    }
+
     #[must_use]
     /// Get the unit name
     pub fn name(&self) -> &str {
@@ -19,4 +28,10 @@ impl Unit {
     pub const fn id(&self) -> Option<&String> {
         self.id.as_ref()
     }
+
+    #[must_use]
+    /// Get the base URLs the repository is served from
+    pub fn mirrors(&self) -> &[String] {
+        &self.mirrors
+    }
 }
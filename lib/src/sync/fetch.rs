@@ -0,0 +1,179 @@
+use std::path::Path;
+
+use crate::{
+    downloader::{DownloadKey, DownloadPool, Sink},
+    repo::{Encoding, File, FileDelta, Layer, ModDelta, Repository},
+};
+
+use super::{Batch, Store, SyncPlan};
+
+/// Bring the files `plan` adds or changes into `target`, from `store` or `repository`
+///
+/// Each file is placed from the store when it has it, and only downloaded otherwise, with
+/// the variant of the first of `accepted` it is published with. Downloaded files are
+/// added to the store once verified, and the uses of the store are saved once every file
+/// is in place. Returns the bytes downloaded.
+pub async fn fetch(
+    plan: &SyncPlan,
+    repository: &Repository,
+    target: &Path,
+    store: &Store,
+    pool: &DownloadPool,
+    accepted: &[Encoding],
+) -> Result<u64, String> {
+    let mirrors = repository.unit().mirrors();
+    let Some(base) = mirrors.first() else {
+        return Err(format!("`{}` has no mirrors", repository.unit().name()));
+    };
+    let mut batch = store.batch()?;
+    // Only for this fetch, the pool may download for other repositories
+    let grouped = mirrors.len() > 1;
+    if grouped {
+        pool.add_mirrors(mirrors.to_vec());
+    }
+    let result = fill(plan, repository, target, base, &mut batch, pool, accepted).await;
+    if grouped {
+        pool.remove_mirrors(base);
+    }
+    // Files already stored are kept track of even if the sync failed
+    batch.commit()?;
+    result
+}
+
+/// Place or download each file of `plan`, using the store through `batch`
+async fn fill(
+    plan: &SyncPlan,
+    repository: &Repository,
+    target: &Path,
+    base: &str,
+    batch: &mut Batch<'_>,
+    pool: &DownloadPool,
+    accepted: &[Encoding],
+) -> Result<u64, String> {
+    let mut pending = Vec::new();
+    for m in plan.mods() {
+        let Some(new) = repository.find_mod(m.name()) else {
+            continue;
+        };
+        for (path, file) in needed(m.delta(), new.root()) {
+            let dest = target.join(m.name()).join(&path);
+            if batch.place(file, &dest)?.is_some() {
+                continue;
+            }
+            let url = format!("{}/{}/{path}", base.trim_end_matches('/'), m.name());
+            let key = DownloadKey::file(url, file, accepted).with_sink(Sink::File(dest.clone()));
+            pending.push((file, dest, pool.download(key).await));
+        }
+    }
+    let mut downloaded = 0;
+    for (file, dest, handle) in pending {
+        handle
+            .wait()
+            .await
+            .map_err(|e| format!("Failed to download `{}`: {e}", dest.display()))?;
+        // Files without the hash of their bytes could only be checked by their size
+        if file.sha256().is_empty() && !file.hash_of(&dest).is_ok_and(|hash| hash == file.hash()) {
+            let _ = std::fs::remove_file(&dest);
+            return Err(format!(
+                "`{}` does not match the repository",
+                dest.display()
+            ));
+        }
+        batch.insert(file, &dest)?;
+        downloaded += file.size();
+    }
+    Ok(downloaded)
+}
+
+/// The files added or changed by `delta`, by their path from the root of the mod
+fn needed<'a>(delta: &ModDelta, root: &'a Layer) -> Vec<(String, &'a File)> {
+    let mut files = Vec::new();
+    match delta {
+        ModDelta::Added => collect(root, "", &mut files),
+        ModDelta::Changed(changed) => {
+            for (path, delta) in changed {
+                if matches!(delta, FileDelta::Deleted) {
+                    continue;
+                }
+                if let Some(file) = root.file(path) {
+                    files.push((path.clone(), file));
+                } else if let Some(layer) = root.layer(path) {
+                    collect(layer, &format!("{path}/"), &mut files);
+                }
+            }
+            files.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        }
+        ModDelta::Removed | ModDelta::Unchanged => {}
+    }
+    files
+}
+
+/// Every file of `layer` and its sublayers, their paths starting with `prefix`
+fn collect<'a>(layer: &'a Layer, prefix: &str, files: &mut Vec<(String, &'a File)>) {
+    for file in layer.files() {
+        files.push((format!("{prefix}{}", file.name()), file));
+    }
+    for sub in layer.layers() {
+        collect(sub, &format!("{prefix}{}/", sub.name()), files);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::Installed,
+        testing::{MockRepository, MockServer, PACK},
+    };
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let mock = MockRepository::new("test")
+            .with_random_file("@ace/addons/ace_common.pbo", 64 * 1024)
+            .with_file("@ace/mod.cpp", "name = \"ACE\";");
+        let server = MockServer::serve(&mock).await.unwrap();
+        let mirror = MockServer::serve(&mock).await.unwrap();
+        let repository = mock.repository(vec![server.url(""), mirror.url("")]);
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        let pool = DownloadPool::new(2, None).await;
+        let plan = SyncPlan::new(
+            &Installed::default(),
+            None,
+            &repository,
+            &[PACK.to_string()],
+        )
+        .unwrap();
+
+        let first = dir.path().join("first");
+        let size = repository.find_mod("@ace").unwrap().size();
+        assert_eq!(
+            fetch(&plan, &repository, &first, &store, &pool, &[])
+                .await
+                .unwrap(),
+            size
+        );
+        for (path, content) in mock.files() {
+            assert_eq!(std::fs::read(first.join(path)).unwrap(), content);
+        }
+        assert_eq!(store.size().unwrap(), size);
+        assert!(!pool.remove_mirrors(&server.url("")));
+
+        // Another install is placed from the store, without a request
+        let second = dir.path().join("second");
+        let requests = server.requests("@ace/mod.cpp");
+        assert_eq!(
+            fetch(&plan, &repository, &second, &store, &pool, &[])
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(server.requests("@ace/mod.cpp"), requests);
+        assert_eq!(
+            std::fs::read(second.join("@ace/mod.cpp")).unwrap(),
+            b"name = \"ACE\";"
+        );
+
+        pool.shutdown().await;
+    }
+}
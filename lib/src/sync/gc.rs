@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::repo::Layer;
+
+use super::{Installed, Subscription};
+
+#[derive(Debug, Default)]
+/// Mods and files no subscribed pack references anymore.
+pub struct Garbage {
+    /// Installed mods that are not in any subscribed pack, with their size.
+    mods: Vec<(String, u64)>,
+    /// Files and folders inside referenced mods that are not in the repository,
+    /// relative to the target, with their size.
+    files: Vec<(PathBuf, u64)>,
+    /// Mods recorded as installed that are no longer in the target.
+    missing: Vec<String>,
+}
+
+impl Garbage {
+    /// Find everything in `target` that is no longer referenced by `subscriptions`
+    ///
+    /// Only mods recorded in `installed` are considered, any other folder is left alone.
+    /// Recorded mods missing from `target` are found too, to be forgotten.
+    pub fn scan(
+        target: &Path,
+        installed: &Installed,
+        subscriptions: &[Subscription],
+    ) -> Result<Self, String> {
+        let mut referenced: HashMap<&str, Vec<&Layer>> = HashMap::new();
+        for subscription in subscriptions {
+            for m in subscription.mods()? {
+                referenced.entry(m.name()).or_default().push(m.root());
+            }
+        }
+        let mut garbage = Self::default();
+        for name in installed.mods() {
+            let path = target.join(name);
+            if !path.exists() {
+                garbage.missing.push(name.clone());
+                continue;
+            }
+            match referenced.get(name.as_str()) {
+                Some(layers) => {
+                    stray(&path, Path::new(name), layers, &mut garbage.files)?;
+                }
+                None => garbage.mods.push((name.clone(), size(&path)?)),
+            }
+        }
+        Ok(garbage)
+    }
+
+    #[must_use]
+    /// Gets the orphaned mods and their size.
+    pub fn mods(&self) -> &[(String, u64)] {
+        &self.mods
+    }
+
+    #[must_use]
+    /// Gets the stray files and their size, relative to the target.
+    pub fn files(&self) -> &[(PathBuf, u64)] {
+        &self.files
+    }
+
+    #[must_use]
+    /// Gets the mods recorded as installed that are no longer in the target.
+    pub fn missing(&self) -> &[String] {
+        &self.missing
+    }
+
+    #[must_use]
+    /// Total bytes that removing the garbage will free
+    pub fn size(&self) -> u64 {
+        self.mods.iter().map(|(_, s)| s).sum::<u64>()
+            + self.files.iter().map(|(_, s)| s).sum::<u64>()
+    }
+
+    /// Remove the garbage from `target`, returning the bytes freed
+    ///
+    /// Removed and missing mods are forgotten from `installed`, which is saved afterwards,
+    /// so the next sync installs the missing ones again.
+    pub fn remove(self, target: &Path, installed: &mut Installed) -> Result<u64, String> {
+        let freed = self.size();
+        for name in &self.missing {
+            installed.remove(name);
+        }
+        for (name, _) in self.mods {
+            let path = target.join(&name);
+            if let Err(e) = std::fs::remove_dir_all(&path) {
+                // Keep the record of the mods removed so far
+                installed.save(target)?;
+                return Err(format!("Failed to remove `{}`: {e}", path.display()));
+            }
+            installed.remove(&name);
+        }
+        installed.save(target)?;
+        for (file, _) in self.files {
+            let path = target.join(file);
+            let result = if path.symlink_metadata().map_err(|e| e.to_string())?.is_dir() {
+                std::fs::remove_dir_all(&path)
+            } else {
+                std::fs::remove_file(&path)
+            };
+            result.map_err(|e| format!("Failed to remove `{}`: {e}", path.display()))?;
+        }
+        Ok(freed)
+    }
+}
+
+/// Collect entries of `path` that are in none of `layers`
+fn stray(
+    path: &Path,
+    relative: &Path,
+    layers: &[&Layer],
+    out: &mut Vec<(PathBuf, u64)>,
+) -> Result<(), String> {
+    let entries = std::fs::read_dir(path)
+        .map_err(|e| format!("Failed to read_dir on `{}`: {e}", path.display()))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // The download may still be resumed
+        if is_download(&name) {
+            continue;
+        }
+        let file_type = entry.file_type().map_err(|e| e.to_string())?;
+        if file_type.is_dir() {
+            let sublayers = layers
+                .iter()
+                .filter_map(|l| l.layers().iter().find(|sl| sl.name() == name))
+                .collect::<Vec<_>>();
+            if sublayers.is_empty() {
+                out.push((relative.join(&name), size(&entry.path())?));
+            } else {
+                stray(&entry.path(), &relative.join(&name), &sublayers, out)?;
+            }
+        } else if !layers
+            .iter()
+            .any(|l| l.files().iter().any(|f| f.name() == name))
+        {
+            out.push((relative.join(&name), size(&entry.path())?));
+        }
+    }
+    Ok(())
+}
+
+/// Is `name` left by an interrupted download, a `.part` file or the sidecar of it or a segment
+fn is_download(name: &str) -> bool {
+    if name.ends_with(".part") || name.ends_with(".part.toml") {
+        return true;
+    }
+    name.rsplit_once(".seg")
+        .is_some_and(|(_, index)| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Size of a file, or everything in a folder
+fn size(path: &Path) -> Result<u64, String> {
+    let metadata = path.symlink_metadata().map_err(|e| e.to_string())?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut total = 0;
+    for entry in std::fs::read_dir(path).map_err(|e| e.to_string())? {
+        total += size(&entry.map_err(|e| e.to_string())?.path())?;
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        repo::{File, Mod, ModDelta, Pack, Repository, Unit},
+        sync::SyncPlan,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_gc() {
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        for (path, content) in [
+            ("@used/keep", "keep"),
+            ("@used/stray", "stray"),
+            ("@used/addons/new.pbo.part", "partial"),
+            ("@used/addons/new.pbo.part.toml", "received = 7"),
+            ("@used/addons/big.pbo.seg2", "segment"),
+            ("@used/addons/old/x", "old"),
+            ("@unused/a", "unused"),
+            ("@manual/b", "manual"),
+        ] {
+            let path = target.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let repository = || {
+            let unit: Unit = toml::from_str("name = \"test\"").unwrap();
+            let mut packs = HashMap::new();
+            packs.insert(
+                "main".to_string(),
+                Pack::new(
+                    "main".to_string(),
+                    vec!["@used".to_string(), "@gone".to_string()],
+                    Vec::new(),
+                ),
+            );
+            let gone = Layer::new(
+                "@gone".to_string(),
+                vec![File::new_generic("x".to_string(), 1, Vec::new())],
+                Vec::new(),
+            );
+            Repository::new(
+                unit,
+                vec![
+                    Mod::new(
+                        "@used".to_string(),
+                        Layer::new(
+                            "@used".to_string(),
+                            vec![File::new_generic("keep".to_string(), 4, Vec::new())],
+                            vec![Layer::new("addons".to_string(), Vec::new(), Vec::new())],
+                        ),
+                    ),
+                    Mod::new("@gone".to_string(), gone),
+                ],
+                packs,
+                Vec::new(),
+                0,
+            )
+        };
+        let mut installed = Installed::default();
+        installed.insert_pack(&repository(), "main").unwrap();
+        installed.insert("@unused".to_string());
+        let subscriptions = [Subscription::new(repository(), vec!["main".to_string()])];
+
+        let garbage = Garbage::scan(target, &installed, &subscriptions).unwrap();
+        assert_eq!(garbage.mods(), &[("@unused".to_string(), 6)]);
+        assert_eq!(garbage.missing(), &["@gone".to_string()]);
+        let mut files = garbage.files().to_vec();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                (PathBuf::from("@used/addons/old"), 3),
+                (PathBuf::from("@used/stray"), 5)
+            ]
+        );
+        assert_eq!(garbage.remove(target, &mut installed).unwrap(), 14);
+        assert!(!target.join("@unused").exists());
+        assert!(!target.join("@used/stray").exists());
+        assert!(target.join("@used/keep").exists());
+        assert!(target.join("@manual/b").exists());
+        assert!(target.join("@used/addons/new.pbo.part").exists());
+        assert!(target.join("@used/addons/big.pbo.seg2").exists());
+        assert!(!Installed::load(target).unwrap().contains("@unused"));
+
+        // A mod deleted by hand is installed again
+        assert!(!Installed::load(target).unwrap().contains("@gone"));
+        let main = ["main".to_string()];
+        let plan = SyncPlan::new(&installed, Some(&repository()), &repository(), &main).unwrap();
+        let gone = plan.mods().iter().find(|m| m.name() == "@gone").unwrap();
+        assert_eq!(gone.delta(), &ModDelta::Added);
+        let used = plan.mods().iter().find(|m| m.name() == "@used").unwrap();
+        assert_eq!(used.delta(), &ModDelta::Unchanged);
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use ring::digest::{Context, SHA256};
+use serde::{Deserialize, Serialize};
+
+use crate::repo::Repository;
+
+use super::STATE_DIR;
+
+const FILE: &str = "installed.toml";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+/// The mods hermes has installed into a folder.
+///
+/// Only mods recorded here are ever removed by hermes.
+pub struct Installed {
+    #[serde(default)]
+    /// The names of installed mods.
+    mods: BTreeSet<String>,
+    #[serde(default)]
+    /// The hash of each installed pack, by unit and pack name.
+    packs: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The install status of a pack.
+pub enum PackStatus {
+    /// The pack has never been installed
+    NotInstalled,
+    /// The pack was installed, but its mods have changed since
+    Outdated,
+    /// The pack is installed and up to date
+    Installed,
+}
+
+impl Installed {
+    /// Load the record from `target`, empty if nothing has been installed yet
+    pub fn load(target: &Path) -> Result<Self, String> {
+        let path = target.join(STATE_DIR).join(FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let source = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read `{}`: {e}", path.display()))?;
+        toml::from_str(&source).map_err(|e| e.to_string())
+    }
+
+    /// Save the record to `target`
+    pub fn save(&self, target: &Path) -> Result<(), String> {
+        let dir = target.join(STATE_DIR);
+        std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let source = toml::to_string(self).map_err(|e| e.to_string())?;
+        std::fs::write(dir.join(FILE), source).map_err(|e| e.to_string())
+    }
+
+    #[must_use]
+    /// Gets the names of installed mods.
+    pub const fn mods(&self) -> &BTreeSet<String> {
+        &self.mods
+    }
+
+    #[must_use]
+    /// Was the mod installed by hermes
+    pub fn contains(&self, name: &str) -> bool {
+        self.mods.contains(name)
+    }
+
+    /// Record a mod as installed
+    pub fn insert(&mut self, name: String) {
+        self.mods.insert(name);
+    }
+
+    /// Forget an installed mod, returns if it was recorded
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.mods.remove(name)
+    }
+
+    #[must_use]
+    /// The install status of a pack from `repository`
+    pub fn status(&self, repository: &Repository, pack: &str) -> PackStatus {
+        let Some(installed) = self
+            .packs
+            .get(repository.unit().name())
+            .and_then(|packs| packs.get(pack))
+        else {
+            return PackStatus::NotInstalled;
+        };
+        if pack_hash(repository, pack).as_ref() == Some(installed) {
+            PackStatus::Installed
+        } else {
+            PackStatus::Outdated
+        }
+    }
+
+    #[must_use]
+    /// Gets the packs of `repository` recorded as installed
+    pub fn packs(&self, repository: &Repository) -> Vec<String> {
+        self.packs
+            .get(repository.unit().name())
+            .map(|packs| {
+                packs
+                    .keys()
+                    .filter(|pack| repository.packs().contains_key(*pack))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Record a pack from `repository` as installed, along with its mods
+    pub fn insert_pack(&mut self, repository: &Repository, pack: &str) -> Result<(), String> {
+        let Some(mods) = repository.pack_mods(pack) else {
+            return Err(format!(
+                "Pack `{pack}` does not exist in `{}`",
+                repository.unit().name()
+            ));
+        };
+        for m in mods {
+            self.mods.insert(m.name().to_string());
+        }
+        if let Some(hash) = pack_hash(repository, pack) {
+            self.packs
+                .entry(repository.unit().name().to_string())
+                .or_default()
+                .insert(pack.to_string(), hash);
+        }
+        Ok(())
+    }
+
+    /// Forget an installed pack, its mods are left for [`super::Garbage`]
+    pub fn remove_pack(&mut self, repository: &Repository, pack: &str) -> bool {
+        self.packs
+            .get_mut(repository.unit().name())
+            .is_some_and(|packs| packs.remove(pack).is_some())
+    }
+}
+
+/// Hash of all mods in a pack, hex encoded
+fn pack_hash(repository: &Repository, pack: &str) -> Option<String> {
+    let mods = repository.pack_mods(pack)?;
+    let mut hash = Context::new(&SHA256);
+    for m in mods {
+        hash.update(m.name().as_bytes());
+        hash.update(m.hash());
+    }
+    Some(
+        hash.finish()
+            .as_ref()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::repo::{File, Layer, Mod, Pack, Unit};
+
+    use super::*;
+
+    fn repo(hash: u8) -> Repository {
+        let unit: Unit = toml::from_str("name = \"test\"").unwrap();
+        let mut packs = HashMap::new();
+        for (name, mods) in [("main", vec!["@a"]), ("extra", vec!["@a", "@b"])] {
+            packs.insert(
+                name.to_string(),
+                Pack::new(
+                    name.to_string(),
+                    mods.into_iter().map(String::from).collect(),
+                    Vec::new(),
+                ),
+            );
+        }
+        let mods = [("@a", 0), ("@b", hash)]
+            .into_iter()
+            .map(|(name, hash)| {
+                let file = File::new_generic("x".to_string(), 1, vec![hash]);
+                Mod::new(
+                    name.to_string(),
+                    Layer::new(name.to_string(), vec![file], Vec::new()),
+                )
+            })
+            .collect();
+        Repository::new(unit, mods, packs, Vec::new(), 0)
+    }
+
+    #[test]
+    fn test_pack_status() {
+        let mut installed = Installed::default();
+        let old = repo(1);
+        installed.insert_pack(&old, "main").unwrap();
+        assert_eq!(installed.status(&old, "main"), PackStatus::Installed);
+        assert_eq!(installed.status(&old, "extra"), PackStatus::NotInstalled);
+        assert!(installed.contains("@a"));
+        assert!(!installed.contains("@b"));
+
+        installed.insert_pack(&old, "extra").unwrap();
+        // Only @b changes, which is not in main
+        let new = repo(2);
+        assert_eq!(installed.status(&new, "main"), PackStatus::Installed);
+        assert_eq!(installed.status(&new, "extra"), PackStatus::Outdated);
+    }
+}
@@ -0,0 +1,351 @@
+#![deny(clippy::all, clippy::nursery, missing_docs)]
+
+//! hermes - Sync
+//!
+//! Client side planning for bringing local mods in line with a repository.
+
+mod fetch;
+mod gc;
+mod installed;
+mod preflight;
+mod store;
+mod subscription;
+mod verify;
+
+pub use fetch::fetch;
+pub use gc::Garbage;
+pub use installed::{Installed, PackStatus};
+pub use preflight::{PreflightError, Space};
+pub use store::{Batch, Link, Store};
+pub use subscription::Subscription;
+pub use verify::verify;
+
+use crate::repo::{FileDelta, Layer, Mod, ModDelta, Repository};
+
+/// Folder inside the target used for hermes' own state
+const STATE_DIR: &str = ".hermes";
+
+#[derive(Debug)]
+/// The changes required to bring the local mods in line with a repository.
+pub struct SyncPlan {
+    /// The changes for each mod.
+    mods: Vec<ModPlan>,
+}
+
+impl SyncPlan {
+    /// Compare the last installed repository with a new one, for the mods used by `packs`
+    ///
+    /// `installed` is `None` when nothing has been installed yet. Only the mods `record` still
+    /// has, of packs it has as installed, are compared, any other mod is installed in full.
+    /// Mods that are no longer used by `packs` but still exist in the repository are left
+    /// for [`Garbage`].
+    pub fn new(
+        record: &Installed,
+        installed: Option<&Repository>,
+        target: &Repository,
+        packs: &[String],
+    ) -> Result<Self, String> {
+        let selected = select(target, packs)?;
+        // A mod forgotten by the record, such as one deleted by hand, is installed again
+        let previous = match installed {
+            Some(installed) => select(installed, &record.packs(installed))?
+                .into_iter()
+                .filter(|m| record.contains(m.name()))
+                .collect(),
+            None => Vec::new(),
+        };
+        let mut mods = Vec::with_capacity(selected.len());
+        for new in selected {
+            let old = previous.iter().find(|m| m.name() == new.name()).copied();
+            mods.push(ModPlan::new(old, Some(new))?);
+        }
+        if let Some(installed) = installed {
+            // Only the selected packs that were installed had anything installed
+            let packs = record
+                .packs(installed)
+                .into_iter()
+                .filter(|p| packs.contains(p))
+                .collect::<Vec<_>>();
+            for old in select(installed, &packs)? {
+                if target.find_mod(old.name()).is_none() && record.contains(old.name()) {
+                    mods.push(ModPlan::new(Some(old), None)?);
+                }
+            }
+        }
+        Ok(Self { mods })
+    }
+
+    #[must_use]
+    /// Gets the changes for each mod.
+    pub fn mods(&self) -> &[ModPlan] {
+        &self.mods
+    }
+
+    #[must_use]
+    /// Total bytes that will be downloaded into staging
+    pub fn download(&self) -> u64 {
+        self.mods.iter().map(ModPlan::download).sum()
+    }
+
+    #[must_use]
+    /// Total bytes freed by deleting files before any download starts
+    pub fn freed(&self) -> u64 {
+        self.mods.iter().map(ModPlan::freed).sum()
+    }
+
+    #[must_use]
+    /// Total bytes of old files that are replaced once staging is committed
+    pub fn replaced(&self) -> u64 {
+        self.mods.iter().map(ModPlan::replaced).sum()
+    }
+
+    #[must_use]
+    /// Free space required to complete the sync
+    ///
+    /// Deleted files are removed before downloading, while replaced files are only
+    /// removed once all staged files are moved into place, so the peak usage is
+    /// everything downloaded less everything deleted.
+    pub fn required(&self) -> u64 {
+        self.download().saturating_sub(self.freed())
+    }
+}
+
+#[derive(Debug)]
+/// The changes required for a single mod.
+pub struct ModPlan {
+    /// The name of the mod.
+    name: String,
+    /// How the mod has changed.
+    delta: ModDelta,
+    /// Bytes to download into staging.
+    download: u64,
+    /// Bytes freed by deleted files.
+    freed: u64,
+    /// Bytes of old files replaced by staged files.
+    replaced: u64,
+}
+
+impl ModPlan {
+    fn new(old: Option<&Mod>, new: Option<&Mod>) -> Result<Self, String> {
+        let (name, delta) = match (old, new) {
+            (Some(old), Some(new)) => (new.name(), ModDelta::new(old, new)?),
+            (None, Some(new)) => (new.name(), ModDelta::Added),
+            (Some(old), None) => (old.name(), ModDelta::Removed),
+            (None, None) => return Err("No mod to plan".to_string()),
+        };
+        let mut plan = Self {
+            name: name.to_string(),
+            delta,
+            download: 0,
+            freed: 0,
+            replaced: 0,
+        };
+        match &plan.delta {
+            ModDelta::Added => plan.download = new.map_or(0, Mod::size),
+            ModDelta::Removed => plan.freed = old.map_or(0, Mod::size),
+            ModDelta::Unchanged => {}
+            ModDelta::Changed(files) => {
+                let old = old.map(Mod::root);
+                let new = new.map(Mod::root);
+                for (path, delta) in files {
+                    match delta {
+                        FileDelta::New => plan.download += size_of(new, path),
+                        FileDelta::Deleted => plan.freed += size_of(old, path),
+                        FileDelta::GenericChanged | FileDelta::PboChanged { .. } => {
+                            plan.download += size_of(new, path);
+                            plan.replaced += size_of(old, path);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(plan)
+    }
+
+    #[must_use]
+    /// Gets the name of the mod.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[must_use]
+    /// Gets how the mod has changed.
+    pub const fn delta(&self) -> &ModDelta {
+        &self.delta
+    }
+
+    #[must_use]
+    /// Bytes to download into staging
+    pub const fn download(&self) -> u64 {
+        self.download
+    }
+
+    #[must_use]
+    /// Bytes freed by deleted files
+    pub const fn freed(&self) -> u64 {
+        self.freed
+    }
+
+    #[must_use]
+    /// Bytes of old files replaced by staged files
+    pub const fn replaced(&self) -> u64 {
+        self.replaced
+    }
+}
+
+/// The mods used by any of `packs`
+fn select<'a>(repository: &'a Repository, packs: &[String]) -> Result<Vec<&'a Mod>, String> {
+    let mut mods: Vec<&Mod> = Vec::new();
+    for pack in packs {
+        let Some(pack_mods) = repository.pack_mods(pack) else {
+            return Err(format!(
+                "Pack `{pack}` does not exist in `{}`",
+                repository.unit().name()
+            ));
+        };
+        for m in pack_mods {
+            if !mods.iter().any(|em| em.name() == m.name()) {
+                mods.push(m);
+            }
+        }
+    }
+    Ok(mods)
+}
+
+/// Size of a file or a whole layer at `path`
+fn size_of(root: Option<&Layer>, path: &str) -> u64 {
+    let Some(root) = root else {
+        return 0;
+    };
+    root.file(path)
+        .map(crate::repo::File::size)
+        .or_else(|| root.layer(path).map(Layer::size))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::repo::{File, Pack, Unit};
+
+    use super::*;
+
+    fn repo(mods: Vec<Mod>) -> Repository {
+        let unit: Unit = toml::from_str("name = \"test\"").unwrap();
+        let mut packs = HashMap::new();
+        packs.insert(
+            "all".to_string(),
+            Pack::new("all".to_string(), vec!["*".to_string()], Vec::new()),
+        );
+        packs.insert(
+            "new".to_string(),
+            Pack::new("new".to_string(), vec!["@new".to_string()], Vec::new()),
+        );
+        Repository::new(unit, mods, packs, Vec::new(), 0)
+    }
+
+    fn generic(name: &str, size: u64, hash: u8) -> File {
+        File::new_generic(name.to_string(), size, vec![hash])
+    }
+
+    #[test]
+    fn test_plan_sizes() {
+        let installed = repo(vec![
+            Mod::new(
+                "@a".to_string(),
+                Layer::new(
+                    "@a".to_string(),
+                    vec![generic("keep", 10, 0), generic("change", 20, 1)],
+                    vec![Layer::new(
+                        "addons".to_string(),
+                        vec![generic("gone.pbo", 30, 2)],
+                        Vec::new(),
+                    )],
+                ),
+            ),
+            Mod::new(
+                "@old".to_string(),
+                Layer::new("@old".to_string(), vec![generic("x", 40, 3)], Vec::new()),
+            ),
+        ]);
+        let target = repo(vec![
+            Mod::new(
+                "@a".to_string(),
+                Layer::new(
+                    "@a".to_string(),
+                    vec![
+                        generic("keep", 10, 0),
+                        generic("change", 25, 4),
+                        generic("new", 5, 5),
+                    ],
+                    vec![Layer::new("addons".to_string(), Vec::new(), Vec::new())],
+                ),
+            ),
+            Mod::new(
+                "@new".to_string(),
+                Layer::new("@new".to_string(), vec![generic("y", 100, 6)], Vec::new()),
+            ),
+        ]);
+        let mut record = Installed::default();
+        record.insert_pack(&installed, "all").unwrap();
+        let plan = SyncPlan::new(&record, Some(&installed), &target, &["all".to_string()]).unwrap();
+        let a = plan.mods().iter().find(|m| m.name() == "@a").unwrap();
+        assert_eq!(a.download(), 30);
+        assert_eq!(a.freed(), 30);
+        assert_eq!(a.replaced(), 20);
+        let old = plan.mods().iter().find(|m| m.name() == "@old").unwrap();
+        assert_eq!(old.delta(), &ModDelta::Removed);
+        assert_eq!(old.freed(), 40);
+        assert_eq!(plan.download(), 130);
+        assert_eq!(plan.freed(), 70);
+        assert_eq!(plan.required(), 60);
+
+        let plan = SyncPlan::new(&record, Some(&installed), &target, &["new".to_string()]).unwrap();
+        assert_eq!(plan.mods().len(), 1);
+        assert_eq!(plan.mods()[0].name(), "@new");
+        assert_eq!(plan.download(), 100);
+    }
+
+    #[test]
+    fn test_plan_packs() {
+        let unit: Unit = toml::from_str("name = \"test\"").unwrap();
+        let mut packs = HashMap::new();
+        for (name, mods) in [("a", vec!["@a"]), ("b", vec!["@new"])] {
+            packs.insert(
+                name.to_string(),
+                Pack::new(
+                    name.to_string(),
+                    mods.into_iter().map(String::from).collect(),
+                    Vec::new(),
+                ),
+            );
+        }
+        let mods = vec![
+            Mod::new(
+                "@a".to_string(),
+                Layer::new("@a".to_string(), vec![generic("x", 10, 0)], Vec::new()),
+            ),
+            Mod::new(
+                "@new".to_string(),
+                Layer::new("@new".to_string(), vec![generic("y", 100, 1)], Vec::new()),
+            ),
+        ];
+        let installed = Repository::new(unit, mods, packs, Vec::new(), 0);
+
+        // Only pack a was installed
+        let mut record = Installed::default();
+        record.insert_pack(&installed, "a").unwrap();
+        let both = ["a".to_string(), "b".to_string()];
+        let plan = SyncPlan::new(&record, Some(&installed), &installed, &both).unwrap();
+        let a = plan.mods().iter().find(|m| m.name() == "@a").unwrap();
+        assert_eq!(a.delta(), &ModDelta::Unchanged);
+        let new = plan.mods().iter().find(|m| m.name() == "@new").unwrap();
+        assert_eq!(new.delta(), &ModDelta::Added);
+        assert_eq!(plan.download(), 100);
+
+        record.insert_pack(&installed, "b").unwrap();
+        let plan = SyncPlan::new(&record, Some(&installed), &installed, &both).unwrap();
+        assert_eq!(plan.download(), 0);
+    }
+}
@@ -0,0 +1,195 @@
+use std::{fmt::Display, path::Path};
+
+use indicatif::HumanBytes;
+
+use super::SyncPlan;
+use crate::downloader::Partial;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Disk space available for a sync.
+pub struct Space {
+    /// Bytes required to complete the sync.
+    required: u64,
+    /// Bytes available on the target filesystem.
+    available: u64,
+}
+
+impl Space {
+    #[must_use]
+    /// Bytes required to complete the sync
+    pub const fn required(&self) -> u64 {
+        self.required
+    }
+
+    #[must_use]
+    /// Bytes available on the target filesystem
+    pub const fn available(&self) -> u64 {
+        self.available
+    }
+}
+
+#[derive(Debug)]
+/// Why a sync can not start.
+pub enum PreflightError {
+    /// The free space of the target could not be read.
+    Io(String),
+    /// There is not enough free space on the target.
+    InsufficientSpace {
+        /// The space required and available.
+        space: Space,
+        /// Each mod with changes, as `(name, download, freed)`.
+        mods: Vec<(String, u64, u64)>,
+    },
+}
+
+impl Display for PreflightError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Failed to read free disk space: {e}"),
+            Self::InsufficientSpace { space, mods } => {
+                write!(
+                    f,
+                    "Not enough disk space: {} required, {} available",
+                    HumanBytes(space.required),
+                    HumanBytes(space.available)
+                )?;
+                for (name, download, freed) in mods {
+                    write!(
+                        f,
+                        "\n  {name}: {} to download, {} freed",
+                        HumanBytes(*download),
+                        HumanBytes(*freed)
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PreflightError {}
+
+impl SyncPlan {
+    /// Check there is enough free space in `target` before downloading anything
+    ///
+    /// Staging lives inside `target`, so both staged and final files are on the same filesystem.
+    /// The bytes interrupted downloads recorded as received, whole or by segment, are not
+    /// downloaded again.
+    pub fn preflight(&self, target: &Path) -> Result<Space, PreflightError> {
+        // The target may not exist yet on a fresh install
+        let existing = target
+            .ancestors()
+            .find(|p| p.exists())
+            .ok_or_else(|| PreflightError::Io(format!("`{}` does not exist", target.display())))?;
+        let available =
+            fs4::available_space(existing).map_err(|e| PreflightError::Io(e.to_string()))?;
+        let resumed = self
+            .mods()
+            .iter()
+            .map(|m| partial(&target.join(m.name())).min(m.download()))
+            .sum::<u64>();
+        let space = Space {
+            required: self.required().saturating_sub(resumed),
+            available,
+        };
+        if space.required <= space.available {
+            return Ok(space);
+        }
+        Err(PreflightError::InsufficientSpace {
+            space,
+            mods: self
+                .mods()
+                .iter()
+                .filter(|m| m.download() != 0 || m.freed() != 0)
+                .map(|m| (m.name().to_string(), m.download(), m.freed()))
+                .collect(),
+        })
+    }
+}
+
+/// Bytes received by the interrupted downloads in `path`
+fn partial(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    let mut total = 0;
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            total += partial(&entry.path());
+        } else if let Some(name) = entry.file_name().to_string_lossy().strip_suffix(".part") {
+            total += Partial::resumable(&path.join(name));
+        }
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::repo::{File, Layer, Mod, Pack, Repository, Unit};
+
+    use super::*;
+    use crate::sync::Installed;
+
+    fn repo(size: u64) -> Repository {
+        let unit: Unit = toml::from_str("name = \"test\"").unwrap();
+        let mut packs = HashMap::new();
+        packs.insert(
+            "main".to_string(),
+            Pack::new("main".to_string(), vec!["@a".to_string()], Vec::new()),
+        );
+        let file = File::new_generic("a.pbo".to_string(), size, vec![0]);
+        let root = Layer::new(
+            "@a".to_string(),
+            Vec::new(),
+            vec![Layer::new("addons".to_string(), vec![file], Vec::new())],
+        );
+        Repository::new(
+            unit,
+            vec![Mod::new("@a".to_string(), root)],
+            packs,
+            Vec::new(),
+            0,
+        )
+    }
+
+    #[test]
+    fn test_preflight() {
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path().join("missing");
+        let packs = ["main".to_string()];
+
+        let plan = SyncPlan::new(&Installed::default(), None, &repo(1000), &packs).unwrap();
+        let space = plan.preflight(&target).unwrap();
+        assert_eq!(space.required(), 1000);
+        assert!(space.available() >= 1000);
+
+        // An interrupted download resumes from what it recorded as received
+        let addons = target.join("@a/addons");
+        std::fs::create_dir_all(&addons).unwrap();
+        let sidecar = "url = \"a\"\nvalidator = \"etag\"\nreceived = 600";
+        std::fs::write(addons.join("a.pbo.part"), vec![0; 600]).unwrap();
+        std::fs::write(addons.join("a.pbo.part.toml"), sidecar).unwrap();
+        assert_eq!(plan.preflight(&target).unwrap().required(), 400);
+
+        // Segments are written in place, the sparse `.part` is longer than what was received
+        std::fs::write(addons.join("a.pbo.part"), vec![0; 2000]).unwrap();
+        assert_eq!(plan.preflight(&target).unwrap().required(), 400);
+        std::fs::remove_file(addons.join("a.pbo.part.toml")).unwrap();
+        std::fs::write(addons.join("a.pbo.seg500"), sidecar).unwrap();
+        assert_eq!(plan.preflight(&target).unwrap().required(), 400);
+        std::fs::write(addons.join("a.pbo.part.toml"), sidecar).unwrap();
+        assert_eq!(plan.preflight(&target).unwrap().required(), 0);
+
+        let plan = SyncPlan::new(&Installed::default(), None, &repo(u64::MAX / 2), &packs).unwrap();
+        let Err(PreflightError::InsufficientSpace { space, mods }) = plan.preflight(&target) else {
+            panic!("A plan larger than the disk passed preflight");
+        };
+        assert_eq!(space.required(), u64::MAX / 2 - 1200);
+        assert_eq!(mods, vec![("@a".to_string(), u64::MAX / 2, 0)]);
+    }
+}
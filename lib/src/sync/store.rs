@@ -0,0 +1,526 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use fs4::fs_std::FileExt;
+use serde::{Deserialize, Serialize};
+
+use crate::repo::File;
+
+/// Records the size, last use and stamp of each file in the store
+const INDEX: &str = "index.toml";
+/// Held exclusively while the index is changed, or shared for the life of a [`Batch`]
+const LOCK: &str = ".lock";
+/// Folder holding the files, by their hash
+const OBJECTS: &str = "objects";
+/// Files modified this recently are hashed again before they are trusted
+const RECENT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How a file was linked to or from the store.
+pub enum Link {
+    /// Hard-linked, both names share the same file.
+    Hardlink,
+    /// Reflinked, sharing the data until either copy is written to.
+    Reflink,
+    /// Copied.
+    Copy,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    #[serde(default)]
+    /// Incremented on each use, orders the entries by their last use.
+    clock: u64,
+    #[serde(default)]
+    /// The files in the store, by their hex encoded hash.
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Index {
+    /// Record a use of the file `key`
+    fn touch(&mut self, key: String, size: u64, stamp: String) {
+        self.clock += 1;
+        self.entries.insert(
+            key,
+            Entry {
+                size,
+                used: self.clock,
+                stamp,
+            },
+        );
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    /// The size of the file.
+    size: u64,
+    /// The clock of the index when the file was last used.
+    used: u64,
+    #[serde(default)]
+    /// The stamp of the file when it last matched its hash.
+    stamp: String,
+}
+
+#[derive(Debug)]
+/// Files shared by every install, by their hash in the repository.
+///
+/// Placing a file from the store may share it with the store, so installs must replace
+/// their files rather than write into them.
+pub struct Store {
+    /// The folder of the store.
+    root: PathBuf,
+}
+
+impl Store {
+    /// Open the store in `root`, creating it if it does not exist yet
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self, String> {
+        let root = root.into();
+        std::fs::create_dir_all(root.join(OBJECTS))
+            .map_err(|e| format!("Failed to create `{}`: {e}", root.display()))?;
+        Ok(Self { root })
+    }
+
+    #[must_use]
+    /// Gets the folder of the store.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    #[must_use]
+    /// Is `file` in the store
+    pub fn contains(&self, file: &File) -> bool {
+        self.update(|index| Ok(self.check(index, &key(file), file).is_some()))
+            .unwrap_or(false)
+    }
+
+    /// Add the file at `path`, already checked to be `file`, to the store
+    ///
+    /// Returns how the file was linked into the store, or `None` if it was already there.
+    pub fn insert(&self, file: &File, path: &Path) -> Result<Option<Link>, String> {
+        self.update(|index| self.insert_into(index, file, path))
+    }
+
+    /// Place `file` from the store at `dest`, replacing what is there
+    ///
+    /// Returns how the file was placed, or `None` if the store does not have it.
+    pub fn place(&self, file: &File, dest: &Path) -> Result<Option<Link>, String> {
+        self.update(|index| self.place_from(index, file, dest))
+    }
+
+    /// Start a batch of uses of the store, saved to its index at once
+    ///
+    /// Waits for any change of the index by another install, such as a prune.
+    pub fn batch(&self) -> Result<Batch<'_>, String> {
+        let lock = self.lock()?;
+        lock.lock_shared()
+            .map_err(|e| format!("Failed to lock the store: {e}"))?;
+        Ok(Batch {
+            store: self,
+            lock,
+            index: self.load()?,
+            changed: BTreeSet::new(),
+        })
+    }
+
+    /// Total bytes of the files in the store
+    pub fn size(&self) -> Result<u64, String> {
+        let lock = self.lock()?;
+        lock.lock_shared()
+            .map_err(|e| format!("Failed to lock the store: {e}"))?;
+        Ok(self.load()?.entries.values().map(|e| e.size).sum())
+    }
+
+    /// Remove the least recently used files until the store is at most `max` bytes
+    ///
+    /// Returns the bytes removed. Files still hard-linked into an install keep using
+    /// their space until they are removed there too.
+    pub fn prune(&self, max: u64) -> Result<u64, String> {
+        self.update(|index| {
+            let mut size = index.entries.values().map(|e| e.size).sum::<u64>();
+            let mut entries = index
+                .entries
+                .iter()
+                .map(|(key, entry)| (entry.used, key.clone(), entry.size))
+                .collect::<Vec<_>>();
+            entries.sort_unstable();
+            let mut removed = 0;
+            for (_, key, entry) in entries {
+                if size <= max {
+                    break;
+                }
+                let object = self.object(&key);
+                match std::fs::remove_file(&object) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(format!("Failed to remove `{}`: {e}", object.display()));
+                    }
+                    _ => {}
+                }
+                index.entries.remove(&key);
+                size -= entry;
+                removed += entry;
+            }
+            Ok(removed)
+        })
+    }
+
+    /// The path of the file with the hash `key`
+    fn object(&self, key: &str) -> PathBuf {
+        self.root
+            .join(OBJECTS)
+            .join(key.get(..2).unwrap_or_default())
+            .join(key)
+    }
+
+    /// The stamp of the object `key` if it still matches `file`
+    ///
+    /// An object whose stamp changed may have been written through one of its hard links,
+    /// so it is hashed again. One that no longer matches is removed with its entry.
+    fn check(&self, index: &mut Index, key: &str, file: &File) -> Option<String> {
+        let object = self.object(key);
+        if let Ok(metadata) = object.metadata() {
+            let current = stamp(&metadata);
+            if metadata.len() == file.size() {
+                let entry = index.entries.get_mut(key);
+                if !current.is_empty() && entry.as_ref().is_some_and(|e| e.stamp == current) {
+                    return Some(current);
+                }
+                if file.hash_of(&object).is_ok_and(|hash| hash == file.hash()) {
+                    if let Some(entry) = entry {
+                        entry.stamp.clone_from(&current);
+                    }
+                    return Some(current);
+                }
+            }
+        }
+        index.entries.remove(key);
+        let _ = std::fs::remove_file(&object);
+        None
+    }
+
+    fn insert_into(
+        &self,
+        index: &mut Index,
+        file: &File,
+        path: &Path,
+    ) -> Result<Option<Link>, String> {
+        let size = path
+            .metadata()
+            .map_err(|e| format!("Failed to read `{}`: {e}", path.display()))?
+            .len();
+        if size != file.size() {
+            return Err(format!(
+                "`{}` is {size} bytes, expected {}",
+                path.display(),
+                file.size()
+            ));
+        }
+        let key = key(file);
+        let object = self.object(&key);
+        let (link, stamp) = match self.check(index, &key, file) {
+            Some(stamp) => (None, stamp),
+            None => {
+                let link = replace(path, &object)?;
+                let metadata = object
+                    .metadata()
+                    .map_err(|e| format!("Failed to read `{}`: {e}", object.display()))?;
+                (Some(link), stamp(&metadata))
+            }
+        };
+        index.touch(key, size, stamp);
+        Ok(link)
+    }
+
+    fn place_from(
+        &self,
+        index: &mut Index,
+        file: &File,
+        dest: &Path,
+    ) -> Result<Option<Link>, String> {
+        let key = key(file);
+        let Some(stamp) = self.check(index, &key, file) else {
+            return Ok(None);
+        };
+        let link = replace(&self.object(&key), dest)?;
+        index.touch(key, file.size(), stamp);
+        Ok(Some(link))
+    }
+
+    /// Open the lock of the store, shared by every install
+    fn lock(&self) -> Result<std::fs::File, String> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.root.join(LOCK))
+            .map_err(|e| format!("Failed to open the lock of the store: {e}"))
+    }
+
+    /// Read the index, the lock must be held
+    fn load(&self) -> Result<Index, String> {
+        let path = self.root.join(INDEX);
+        match std::fs::read_to_string(&path) {
+            Ok(source) => toml::from_str(&source).map_err(|e| e.to_string()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Index::default()),
+            Err(e) => Err(format!("Failed to read `{}`: {e}", path.display())),
+        }
+    }
+
+    /// Use the index while holding the lock of the store, saving it afterwards
+    fn update<T>(&self, f: impl FnOnce(&mut Index) -> Result<T, String>) -> Result<T, String> {
+        let lock = self.lock()?;
+        lock.lock_exclusive()
+            .map_err(|e| format!("Failed to lock the store: {e}"))?;
+        let path = self.root.join(INDEX);
+        let mut index = self.load()?;
+        let result = f(&mut index)?;
+        let source = toml::to_string(&index).map_err(|e| e.to_string())?;
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, source)
+            .and_then(|()| std::fs::rename(&temp, &path))
+            .map_err(|e| format!("Failed to write `{}`: {e}", path.display()))?;
+        Ok(result)
+    }
+}
+
+/// Uses of a [`Store`] by a single sync, saved to its index at once by [`Batch::commit`].
+///
+/// The store stays locked, shared with the batches of other installs, until the batch is
+/// committed or dropped, so no file is pruned while it is placed. Uses not committed are
+/// lost, but the files placed stay in place.
+pub struct Batch<'a> {
+    store: &'a Store,
+    /// The shared lock of the store.
+    lock: std::fs::File,
+    /// The index as read when the batch started, with the uses of the batch.
+    index: Index,
+    /// The keys of the entries used or dropped by the batch.
+    changed: BTreeSet<String>,
+}
+
+impl Batch<'_> {
+    /// Add the file at `path`, already checked to be `file`, to the store
+    ///
+    /// Same as [`Store::insert`].
+    pub fn insert(&mut self, file: &File, path: &Path) -> Result<Option<Link>, String> {
+        self.changed.insert(key(file));
+        self.store.insert_into(&mut self.index, file, path)
+    }
+
+    /// Place `file` from the store at `dest`, replacing what is there
+    ///
+    /// Same as [`Store::place`].
+    pub fn place(&mut self, file: &File, dest: &Path) -> Result<Option<Link>, String> {
+        self.changed.insert(key(file));
+        self.store.place_from(&mut self.index, file, dest)
+    }
+
+    /// Save the uses of the batch to the index of the store
+    pub fn commit(self) -> Result<(), String> {
+        let Self {
+            store,
+            lock,
+            mut index,
+            changed,
+        } = self;
+        // Saving takes the lock exclusively
+        drop(lock);
+        store.update(|saved| {
+            let mut used = Vec::new();
+            for key in changed {
+                match index.entries.remove(&key) {
+                    Some(entry) => used.push((key, entry)),
+                    None => {
+                        saved.entries.remove(&key);
+                    }
+                }
+            }
+            // Keep the order the files were used in
+            used.sort_unstable_by_key(|(_, entry)| entry.used);
+            for (key, entry) in used {
+                saved.touch(key, entry.size, entry.stamp);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// What the metadata of a file looks like, changing when the file is written to
+///
+/// Empty for a file modified in the last seconds, as a write right after it could leave
+/// the same modification time.
+fn stamp(metadata: &Metadata) -> String {
+    let Some(modified) = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+    else {
+        return String::new();
+    };
+    if SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .is_ok_and(|now| now.saturating_sub(modified) < RECENT)
+    {
+        return String::new();
+    }
+    let modified = modified.as_nanos();
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+    #[cfg(not(unix))]
+    let inode = 0;
+    format!("{}:{modified}:{inode}", metadata.len())
+}
+
+/// Hash of a file, hex encoded
+fn key(file: &File) -> String {
+    file.hash().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Link or copy `from` to `to`, only replacing `to` once done
+fn replace(from: &Path, to: &Path) -> Result<Link, String> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create `{}`: {e}", parent.display()))?;
+    }
+    // Other installs may be linking the same file
+    let mut temp = to.as_os_str().to_owned();
+    temp.push(format!(".{}.tmp", std::process::id()));
+    let temp = PathBuf::from(temp);
+    let _ = std::fs::remove_file(&temp);
+    let link = if std::fs::hard_link(from, &temp).is_ok() {
+        Link::Hardlink
+    } else if reflink_copy::reflink(from, &temp).is_ok() {
+        Link::Reflink
+    } else {
+        std::fs::copy(from, &temp).map_err(|e| {
+            format!(
+                "Failed to copy `{}` to `{}`: {e}",
+                from.display(),
+                temp.display()
+            )
+        })?;
+        Link::Copy
+    };
+    std::fs::rename(&temp, to)
+        .map_err(|e| format!("Failed to move `{}` into place: {e}", to.display()))?;
+    // Renaming onto another link of the same file leaves both names in place
+    let _ = std::fs::remove_file(&temp);
+    Ok(link)
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::digest::{digest, SHA256};
+
+    use super::*;
+
+    fn generic(name: &str, content: &str) -> File {
+        let hash = digest(&SHA256, content.as_bytes()).as_ref().to_vec();
+        File::new_generic(name.to_string(), content.len() as u64, hash)
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let store = Store::open(dir.join("store")).unwrap();
+        let a = generic("a.pbo", "hello");
+        let b = generic("b.pbo", "bbb");
+        let c = generic("c.pbo", "ccc");
+        for (file, content) in [("a.pbo", "hello"), ("b.pbo", "bbb"), ("c.pbo", "ccc")] {
+            let path = dir.join("@cba/addons").join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let addons = dir.join("@cba/addons");
+
+        assert!(!store.contains(&a));
+        assert_eq!(store.place(&a, &dir.join("@ace/a.pbo")).unwrap(), None);
+        assert_eq!(
+            store.insert(&a, &addons.join("a.pbo")).unwrap(),
+            Some(Link::Hardlink)
+        );
+        assert!(store.contains(&a));
+        assert_eq!(store.insert(&a, &addons.join("a.pbo")).unwrap(), None);
+        assert!(store.insert(&a, &addons.join("b.pbo")).is_err());
+
+        // Another install gets the file without downloading it
+        let placed = dir.join("other/@cba/addons/a.pbo");
+        assert_eq!(store.place(&a, &placed).unwrap(), Some(Link::Hardlink));
+        assert_eq!(std::fs::read_to_string(&placed).unwrap(), "hello");
+        assert_eq!(store.place(&a, &placed).unwrap(), Some(Link::Hardlink));
+        assert_eq!(
+            std::fs::read_dir(dir.join("other/@cba/addons"))
+                .unwrap()
+                .count(),
+            1
+        );
+
+        // The least recently used files are pruned first
+        store.insert(&b, &addons.join("b.pbo")).unwrap();
+        store.insert(&c, &addons.join("c.pbo")).unwrap();
+        store.place(&a, &placed).unwrap();
+        assert_eq!(store.size().unwrap(), 11);
+        assert_eq!(store.prune(8).unwrap(), 3);
+        assert!(store.contains(&a));
+        assert!(!store.contains(&b));
+        assert!(store.contains(&c));
+
+        // A file changed through one of its links is dropped, even keeping its size
+        std::fs::write(addons.join("c.pbo"), "CCC").unwrap();
+        assert_eq!(store.place(&c, &dir.join("other/c.pbo")).unwrap(), None);
+        assert_eq!(store.size().unwrap(), 5);
+        std::fs::write(addons.join("a.pbo"), "changed").unwrap();
+        assert!(!store.contains(&a));
+        assert_eq!(store.size().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let store = Store::open(dir.join("store")).unwrap();
+        let a = generic("a.pbo", "hello");
+        let b = generic("b.pbo", "bbb");
+        std::fs::write(dir.join("a.pbo"), "hello").unwrap();
+        std::fs::write(dir.join("b.pbo"), "bbb").unwrap();
+        store.insert(&a, &dir.join("a.pbo")).unwrap();
+
+        // The index is only saved once the batch is committed
+        let mut batch = store.batch().unwrap();
+        assert_eq!(
+            batch.place(&a, &dir.join("other/a.pbo")).unwrap(),
+            Some(Link::Hardlink)
+        );
+        assert_eq!(batch.place(&b, &dir.join("other/b.pbo")).unwrap(), None);
+        assert_eq!(
+            batch.insert(&b, &dir.join("b.pbo")).unwrap(),
+            Some(Link::Hardlink)
+        );
+        assert_eq!(store.size().unwrap(), 5);
+        batch.commit().unwrap();
+        assert_eq!(store.size().unwrap(), 8);
+
+        // Uses in a batch are as recent as its commit
+        store.insert(&a, &dir.join("a.pbo")).unwrap();
+        let mut batch = store.batch().unwrap();
+        batch.place(&b, &dir.join("other/b.pbo")).unwrap();
+        batch.commit().unwrap();
+        assert_eq!(store.prune(3).unwrap(), 5);
+        assert!(store.contains(&b));
+
+        // Nothing is pruned while a batch may be placing it
+        let batch = store.batch().unwrap();
+        let root = store.root().to_path_buf();
+        let prune = std::thread::spawn(move || Store::open(root).unwrap().prune(0));
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!prune.is_finished());
+        batch.commit().unwrap();
+        assert_eq!(prune.join().unwrap().unwrap(), 3);
+    }
+}
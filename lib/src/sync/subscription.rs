@@ -0,0 +1,35 @@
+use crate::repo::{Mod, Repository};
+
+#[derive(Debug)]
+/// A repository the client is subscribed to, and the packs it plays.
+pub struct Subscription {
+    /// The last downloaded repository.
+    repository: Repository,
+    /// The names of the subscribed packs.
+    packs: Vec<String>,
+}
+
+impl Subscription {
+    #[must_use]
+    /// Creates a new subscription.
+    pub const fn new(repository: Repository, packs: Vec<String>) -> Self {
+        Self { repository, packs }
+    }
+
+    #[must_use]
+    /// Gets the repository.
+    pub const fn repository(&self) -> &Repository {
+        &self.repository
+    }
+
+    #[must_use]
+    /// Gets the names of the subscribed packs.
+    pub fn packs(&self) -> &[String] {
+        &self.packs
+    }
+
+    /// The mods used by any of the subscribed packs
+    pub fn mods(&self) -> Result<Vec<&Mod>, String> {
+        super::select(&self.repository, &self.packs)
+    }
+}
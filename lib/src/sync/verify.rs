@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::repo::{File, Layer, Repository};
+
+/// Check the local files of the mods used by `packs` against `repository`
+///
+/// Returns each mod with missing or corrupt files, as paths relative to the mod folder.
+/// Mods that are not used by `packs` are not read.
+pub fn verify(
+    target: &Path,
+    repository: &Repository,
+    packs: &[String],
+) -> Result<Vec<(String, Vec<String>)>, String> {
+    let mods = super::select(repository, packs)?;
+    let results = mods
+        .par_iter()
+        .map(|m| {
+            let mut bad = Vec::new();
+            check_layer(&target.join(m.name()), "", m.root(), &mut bad)?;
+            Ok((m.name().to_string(), bad))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(results
+        .into_iter()
+        .filter(|(_, bad)| !bad.is_empty())
+        .collect())
+}
+
+fn check_layer(
+    path: &Path,
+    relative: &str,
+    layer: &Layer,
+    bad: &mut Vec<String>,
+) -> Result<(), String> {
+    for file in layer.files() {
+        let name = format!("{relative}{}", file.name());
+        let local = path.join(file.name());
+        let Ok(metadata) = local.metadata() else {
+            bad.push(name);
+            continue;
+        };
+        // Only hash files that could match, a file that can't be read is as bad as missing
+        if metadata.len() != file.size()
+            || !File::from(local).is_ok_and(|local| local.hash() == file.hash())
+        {
+            bad.push(name);
+        }
+    }
+    for sub in layer.layers() {
+        check_layer(
+            &path.join(sub.name()),
+            &format!("{relative}{}/", sub.name()),
+            sub,
+            bad,
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,353 @@
+//! A repository served from memory by a local HTTP server, to test downloads and syncs
+//! offline.
+//!
+//! Only built for the crate's own tests, or with the `testing` feature.
+
+use std::collections::{BTreeMap, HashMap};
+
+use ring::digest::{digest, SHA256};
+
+use crate::repo::{File, Layer, Mod, Pack, Repository, Unit};
+
+mod server;
+
+pub use server::{Fault, MockServer};
+
+/// The path the repository is served at, as written by `hermes generate`.
+pub const REPOSITORY: &str = "hermes.mpk";
+
+/// The pack every mod of a [`MockRepository`] is in.
+pub const PACK: &str = "main";
+
+#[derive(Clone, Debug)]
+/// A repository generated in memory, to be served by a [`MockServer`].
+pub struct MockRepository {
+    name: String,
+    /// The content of each file, by its path from the root of the repository.
+    files: BTreeMap<String, Vec<u8>>,
+    /// Generates the same random files on every run.
+    rng: fastrand::Rng,
+}
+
+impl MockRepository {
+    #[must_use]
+    /// Creates an empty repository for the unit `name`.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            files: BTreeMap::new(),
+            rng: fastrand::Rng::with_seed(0),
+        }
+    }
+
+    #[must_use]
+    /// Add a file at `path`, such as `@ace/addons/ace_common.pbo`.
+    ///
+    /// Files outside of a mod folder are served, but not part of the repository.
+    pub fn with_file(mut self, path: impl Into<String>, content: impl Into<Vec<u8>>) -> Self {
+        self.files.insert(path.into(), content.into());
+        self
+    }
+
+    #[must_use]
+    /// Add a file of `size` random bytes at `path`.
+    pub fn with_random_file(mut self, path: impl Into<String>, size: usize) -> Self {
+        let mut content = vec![0; size];
+        self.rng.fill(&mut content);
+        self.with_file(path, content)
+    }
+
+    #[must_use]
+    /// Gets the content of the file at `path`.
+    pub fn file(&self, path: &str) -> Option<&[u8]> {
+        self.files.get(path).map(Vec::as_slice)
+    }
+
+    /// Gets the path and content of every file.
+    pub fn files(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.files
+            .iter()
+            .map(|(path, content)| (path.as_str(), content.as_slice()))
+    }
+
+    #[must_use]
+    /// Build the repository served from `mirrors`, with every mod in the [`PACK`] pack.
+    ///
+    /// Every file is listed as a generic file, hashed by its bytes.
+    pub fn repository(&self, mirrors: Vec<String>) -> Repository {
+        let mut mods = BTreeMap::<&str, Vec<(&str, &[u8])>>::new();
+        for (path, content) in self.files() {
+            if let Some((name, rest)) = path.split_once('/') {
+                mods.entry(name).or_default().push((rest, content));
+            }
+        }
+        let mods = mods
+            .into_iter()
+            .map(|(name, files)| Mod::new(name.to_string(), layer(name, files)))
+            .collect::<Vec<_>>();
+        let mut packs = HashMap::new();
+        packs.insert(
+            PACK.to_string(),
+            Pack::new(
+                PACK.to_string(),
+                mods.iter().map(|m| m.name().to_string()).collect(),
+                Vec::new(),
+            ),
+        );
+        Repository::new(
+            Unit::new(self.name.clone(), None, mirrors),
+            mods,
+            packs,
+            Vec::new(),
+            0,
+        )
+    }
+}
+
+/// Build the layer `name` from its files, by their path from it.
+fn layer(name: &str, files: Vec<(&str, &[u8])>) -> Layer {
+    let mut own = Vec::new();
+    let mut layers = BTreeMap::<&str, Vec<(&str, &[u8])>>::new();
+    for (path, content) in files {
+        match path.split_once('/') {
+            Some((layer, rest)) => layers.entry(layer).or_default().push((rest, content)),
+            None => own.push(File::new_generic(
+                path.to_string(),
+                content.len() as u64,
+                digest(&SHA256, content).as_ref().to_vec(),
+            )),
+        }
+    }
+    Layer::new(
+        name.to_string(),
+        own,
+        layers
+            .into_iter()
+            .map(|(name, files)| layer(name, files))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::downloader::{
+        AutoTune, ClientConfig, DownloadError, DownloadKey, DownloadPool, Output, RetryPolicy,
+        Sink, Timeout,
+    };
+
+    #[tokio::test]
+    async fn test_server() {
+        let repository = MockRepository::new("test")
+            .with_random_file("@ace/addons/ace_common.pbo", 256 * 1024)
+            .with_file("@ace/mod.cpp", "name = \"ACE\";")
+            .with_file("readme.txt", "not a mod");
+        let server = MockServer::serve(&repository).await.unwrap();
+        let pool = DownloadPool::new(2, None).await;
+        pool.set_retry_policy(RetryPolicy::new(
+            8,
+            Duration::from_millis(10),
+            Duration::from_millis(50),
+        ));
+
+        let Ok(Output::Memory(blob)) = pool
+            .download(DownloadKey::new(server.url(REPOSITORY), None))
+            .await
+            .wait()
+            .await
+        else {
+            panic!("Failed to download the repository");
+        };
+        let served = Repository::from_blob(&blob).unwrap();
+        assert_eq!(served.unit().mirrors(), &[server.url("")]);
+        assert_eq!(served.pack_mods(PACK).unwrap().len(), 1);
+        let file = served
+            .find_mod("@ace")
+            .unwrap()
+            .root()
+            .file("addons/ace_common.pbo");
+        assert_eq!(file.unwrap().size(), 256 * 1024);
+
+        // Ranges are served
+        let path = "@ace/addons/ace_common.pbo";
+        let content = repository.file(path).unwrap();
+        let Ok(Output::Memory(range)) = pool
+            .download(DownloadKey::new(server.url(path), Some((100, 199))))
+            .await
+            .wait()
+            .await
+        else {
+            panic!("Failed to download the range");
+        };
+        assert_eq!(&range[..], &content[100..200]);
+        let response = reqwest::Client::new()
+            .get(server.url(path))
+            .header(reqwest::header::RANGE, "bytes=200-100")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 416);
+
+        // Each fault is retried, the download resuming after the reset
+        let key = DownloadKey::new(server.url(path), None)
+            .with_size(content.len() as u64)
+            .with_sha256(digest(&SHA256, content).as_ref().to_vec());
+        server.inject(path, Fault::Latency(Duration::from_millis(50)), Some(1));
+        server.inject(path, Fault::Status(503), Some(2));
+        server.inject(path, Fault::Reset(100 * 1024), Some(1));
+        let requests = server.requests(path);
+        let Ok(Output::Memory(downloaded)) = pool.download(key.clone()).await.wait().await else {
+            panic!("Failed to download through the faults");
+        };
+        assert_eq!(&downloaded[..], content);
+        assert!(server.requests(path) - requests >= 4);
+
+        // Wrong bytes fail verification and are downloaded again
+        server.inject(path, Fault::Corrupt, Some(1));
+        let requests = server.requests(path);
+        let Ok(Output::Memory(downloaded)) = pool.download(key).await.wait().await else {
+            panic!("Failed to download the corrupted file");
+        };
+        assert_eq!(&downloaded[..], content);
+        assert_eq!(server.requests(path) - requests, 2);
+
+        // Nothing follows the head of a response to HEAD, even with a reset
+        server.inject(path, Fault::Reset(100), Some(1));
+        let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+        stream
+            .write_all(format!("HEAD /{path} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        if let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+            assert_eq!(response.len(), end + 4);
+        }
+
+        // Faults injected for every request apply until cleared
+        server.inject("*", Fault::Status(404), None);
+        assert!(pool
+            .download(DownloadKey::new(server.url("readme.txt"), None))
+            .await
+            .wait()
+            .await
+            .is_err());
+        server.clear();
+        let Ok(Output::Memory(readme)) = pool
+            .download(DownloadKey::new(server.url("readme.txt"), None))
+            .await
+            .wait()
+            .await
+        else {
+            panic!("Failed to download after clearing the faults");
+        };
+        assert_eq!(&readme[..], b"not a mod");
+
+        pool.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_segments() {
+        let path = "@ace/addons/ace_common.pbo";
+        let repository = MockRepository::new("test").with_random_file(path, 256 * 1024);
+        let content = repository.file(path).unwrap();
+        let server = MockServer::serve(&repository).await.unwrap();
+        let pool = DownloadPool::new(4, None).await;
+        pool.set_segment_size(Some(64 * 1024));
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("ace_common.pbo");
+        let key = DownloadKey::new(server.url(path), None)
+            .with_size(content.len() as u64)
+            .with_sha256(digest(&SHA256, content).as_ref().to_vec())
+            .with_sink(Sink::File(dest.clone()));
+
+        // The segments are written in place, leaving only the file
+        let requests = server.requests(path);
+        assert!(pool.download(key.clone()).await.wait().await.is_ok());
+        assert_eq!(std::fs::read(&dest).unwrap(), content);
+        assert_eq!(server.requests(path) - requests, 4);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // A corrupt segment fails the whole, which is downloaded again in one stream
+        std::fs::remove_file(&dest).unwrap();
+        server.inject(path, Fault::Corrupt, Some(1));
+        let requests = server.requests(path);
+        assert!(pool.download(key).await.wait().await.is_ok());
+        assert_eq!(std::fs::read(&dest).unwrap(), content);
+        assert_eq!(server.requests(path) - requests, 5);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        pool.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_timeouts() {
+        let server = MockServer::start([("a.pbo".to_string(), b"a".to_vec())])
+            .await
+            .unwrap();
+        let pool = DownloadPool::new(1, None).await;
+        pool.set_retry_policy(RetryPolicy::new(
+            1,
+            Duration::from_millis(10),
+            Duration::from_millis(10),
+        ));
+        pool.set_client_config(
+            ClientConfig::default()
+                .with_connect_timeout(Duration::from_millis(50))
+                .with_read_timeout(Duration::from_millis(50)),
+        )
+        .unwrap();
+
+        // A server slow to respond isn't reported as a stalled transfer
+        server.inject("a.pbo", Fault::Latency(Duration::from_secs(1)), Some(1));
+        let result = pool
+            .download(DownloadKey::new(server.url("a.pbo"), None))
+            .await
+            .wait()
+            .await;
+        assert_eq!(
+            result.err(),
+            Some(DownloadError::Timeout(Timeout::Response))
+        );
+
+        pool.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_auto_tune() {
+        let mut repository = MockRepository::new("test");
+        for i in 0..8 {
+            repository = repository.with_random_file(format!("@mod/{i}.pbo"), 1024);
+        }
+        let server = MockServer::serve(&repository).await.unwrap();
+        let pool = DownloadPool::new(8, None).await;
+        pool.set_retry_policy(RetryPolicy::new(
+            100,
+            Duration::from_millis(10),
+            Duration::from_millis(10),
+        ));
+        pool.set_auto_tune(Some(
+            AutoTune::new(1, 8).with_interval(Duration::from_millis(100)),
+        ));
+
+        // Errors that are retried still count against the concurrency
+        server.inject("*", Fault::Status(503), None);
+        let mut handles = Vec::new();
+        for i in 0..8 {
+            let url = server.url(&format!("@mod/{i}.pbo"));
+            handles.push(pool.download(DownloadKey::new(url, None)).await);
+        }
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        assert!(pool.max_concurrent() < 8);
+
+        server.clear();
+        for handle in handles {
+            assert!(handle.wait().await.is_ok());
+        }
+        pool.shutdown().await;
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use reqwest::StatusCode;
+use ring::digest::{digest, SHA256};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use super::{MockRepository, REPOSITORY};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// A fault injected into the responses of a [`MockServer`].
+pub enum Fault {
+    /// Wait this long before responding.
+    Latency(Duration),
+    /// Reset the connection after sending this many bytes of the body.
+    Reset(u64),
+    /// Send the body with the bits of its first byte flipped.
+    Corrupt,
+    /// Respond with this status and no body instead.
+    Status(u16),
+}
+
+/// A fault and the requests it applies to.
+struct Rule {
+    /// The path of the file, `*` for every file.
+    path: String,
+    fault: Fault,
+    /// The requests left to apply to, `None` for every request.
+    times: Option<u32>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The content of each file and its `ETag`, by path.
+    files: RwLock<HashMap<String, (Bytes, String)>>,
+    /// Where requests are redirected to, by path.
+    redirects: RwLock<HashMap<String, String>>,
+    rules: Mutex<Vec<Rule>>,
+    requests: Mutex<HashMap<String, u32>>,
+}
+
+impl State {
+    fn file(&self, path: &str) -> Option<(Bytes, String)> {
+        self.files.read().unwrap().get(path).cloned()
+    }
+
+    /// Count a request for `path`, and take the faults to apply to it.
+    fn faults(&self, path: &str) -> Vec<Fault> {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry(path.to_string())
+            .or_default() += 1;
+        let mut rules = self.rules.lock().unwrap();
+        let mut faults = Vec::new();
+        for rule in rules
+            .iter_mut()
+            .filter(|rule| rule.path == "*" || rule.path == path)
+        {
+            if let Some(times) = &mut rule.times {
+                *times -= 1;
+            }
+            faults.push(rule.fault);
+            // The response ends here, later faults are left for the next requests
+            if matches!(rule.fault, Fault::Status(_)) {
+                break;
+            }
+        }
+        rules.retain(|rule| rule.times != Some(0));
+        faults
+    }
+}
+
+/// An HTTP server on localhost, serving files from memory.
+///
+/// Supports `Range` and `If-Range` requests like a static file server would, and answers
+/// each request on its own connection. Stops when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<State>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Serve `files`, by their path from the root of the server.
+    pub async fn start(files: impl IntoIterator<Item = (String, Vec<u8>)>) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(State::default());
+        let task = tokio::spawn(accept(listener, state.clone()));
+        let server = Self { addr, state, task };
+        for (path, content) in files {
+            server.insert(&path, content);
+        }
+        Ok(server)
+    }
+
+    /// Serve `repository` and its files, listing this server as its mirror.
+    ///
+    /// The repository itself is served at [`REPOSITORY`].
+    pub async fn serve(repository: &MockRepository) -> io::Result<Self> {
+        let server = Self::start(
+            repository
+                .files()
+                .map(|(path, content)| (path.to_string(), content.to_vec())),
+        )
+        .await?;
+        let blob = repository.repository(vec![server.url("")]).to_blob();
+        server.insert(REPOSITORY, blob);
+        Ok(server)
+    }
+
+    #[must_use]
+    /// Gets the address the server listens on.
+    pub const fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    #[must_use]
+    /// Gets the URL of the file at `path`.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}/{}", self.addr, path.trim_start_matches('/'))
+    }
+
+    /// Serve `content` at `path`, replacing the file and its `ETag` if there was one.
+    pub fn insert(&self, path: &str, content: impl Into<Vec<u8>>) {
+        let content = Bytes::from(content.into());
+        let hash = digest(&SHA256, &content);
+        let etag = hash.as_ref()[..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        self.state.files.write().unwrap().insert(
+            path.trim_start_matches('/').to_string(),
+            (content, format!("\"{etag}\"")),
+        );
+    }
+
+    /// Stop serving the file at `path`, returns `true` if it was served.
+    pub fn remove(&self, path: &str) -> bool {
+        self.state
+            .files
+            .write()
+            .unwrap()
+            .remove(path.trim_start_matches('/'))
+            .is_some()
+    }
+
+    /// Answer requests for `path` with a redirect to `location`, rather than the file.
+    pub fn redirect(&self, path: &str, location: impl Into<String>) {
+        self.state
+            .redirects
+            .write()
+            .unwrap()
+            .insert(path.trim_start_matches('/').to_string(), location.into());
+    }
+
+    /// Inject `fault` into the next `times` requests for `path`, or every request if `None`.
+    ///
+    /// Use `*` as the path for requests of any file. The faults matching a request are
+    /// applied to it in the order they were injected, up to the first [`Fault::Status`].
+    pub fn inject(&self, path: &str, fault: Fault, times: Option<u32>) {
+        if times == Some(0) {
+            return;
+        }
+        self.state.rules.lock().unwrap().push(Rule {
+            path: path.trim_start_matches('/').to_string(),
+            fault,
+            times,
+        });
+    }
+
+    /// Remove every fault that was injected.
+    pub fn clear(&self) {
+        self.state.rules.lock().unwrap().clear();
+    }
+
+    #[must_use]
+    /// Gets the number of requests received for `path`.
+    pub fn requests(&self, path: &str) -> u32 {
+        self.state
+            .requests
+            .lock()
+            .unwrap()
+            .get(path.trim_start_matches('/'))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn accept(listener: TcpListener, state: Arc<State>) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            // A client hanging up is not a problem of the server
+            let _ = handle(&state, stream).await;
+        });
+    }
+}
+
+async fn handle(state: &State, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(());
+    };
+    let head = method == "HEAD";
+    let path = target
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches('/')
+        .to_string();
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+    let mut stream = reader.into_inner();
+
+    let mut reset = None;
+    let mut corrupt = false;
+    for fault in state.faults(&path) {
+        match fault {
+            Fault::Latency(delay) => tokio::time::sleep(delay).await,
+            Fault::Status(status) => return respond(&mut stream, status, &[], &[]).await,
+            Fault::Reset(after) => reset = Some(after),
+            Fault::Corrupt => corrupt = true,
+        }
+    }
+
+    let location = state.redirects.read().unwrap().get(&path).cloned();
+    if let Some(location) = location {
+        return respond(&mut stream, 302, &[("location", &location)], &[]).await;
+    }
+    let Some((content, etag)) = state.file(&path) else {
+        return respond(&mut stream, 404, &[], &[]).await;
+    };
+    let len = content.len() as u64;
+    // A range of another version of the file is answered with all of this one
+    let range = headers
+        .get("range")
+        .filter(|_| headers.get("if-range").is_none_or(|v| *v == etag))
+        .and_then(|value| range(value, len));
+    let (status, start, end) = match range {
+        Some((start, end)) if start >= len || end.is_some_and(|end| end < start) => {
+            let total = format!("bytes */{len}");
+            return respond(&mut stream, 416, &[("content-range", &total)], &[]).await;
+        }
+        Some((start, end)) => (206, start, end.unwrap_or(u64::MAX).min(len - 1) + 1),
+        None => (200, 0, len),
+    };
+    let mut body = content.slice(start as usize..end as usize).to_vec();
+    if corrupt {
+        if let Some(first) = body.first_mut() {
+            *first ^= 0xff;
+        }
+    }
+    let content_range = format!("bytes {start}-{}/{len}", end.saturating_sub(1));
+    let mut extra = vec![("etag", etag.as_str()), ("accept-ranges", "bytes")];
+    if status == 206 {
+        extra.push(("content-range", &content_range));
+    }
+    write_head(&mut stream, status, &extra, body.len()).await?;
+    // A response to HEAD has no body, even one cut short
+    let body = if head { &body[..0] } else { &body[..] };
+    let Some(after) = reset else {
+        stream.write_all(body).await?;
+        return stream.shutdown().await;
+    };
+    let after = (after as usize).min(body.len());
+    stream.write_all(&body[..after]).await?;
+    stream.flush().await?;
+    // Closing without lingering sends a reset rather than the end of the stream
+    stream.set_linger(Some(Duration::ZERO))?;
+    Ok(())
+}
+
+/// Parse a `bytes=` range, the start and inclusive end if it has one.
+fn range(value: &str, len: u64) -> Option<(u64, Option<u64>)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    if start.is_empty() {
+        let suffix = end.parse::<u64>().ok()?;
+        return Some((len.saturating_sub(suffix), None));
+    }
+    let start = start.parse().ok()?;
+    let end = if end.is_empty() {
+        None
+    } else {
+        Some(end.parse().ok()?)
+    };
+    Some((start, end))
+}
+
+async fn write_head(
+    stream: &mut TcpStream,
+    status: u16,
+    headers: &[(&str, &str)],
+    len: usize,
+) -> io::Result<()> {
+    let reason = StatusCode::from_u16(status)
+        .ok()
+        .and_then(|s| s.canonical_reason())
+        .unwrap_or_default();
+    let mut head =
+        format!("HTTP/1.1 {status} {reason}\r\ncontent-length: {len}\r\nconnection: close\r\n");
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: u16,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> io::Result<()> {
+    write_head(stream, status, headers, body.len()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}